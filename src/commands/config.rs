@@ -1,32 +1,24 @@
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::{BufRead, BufReader},
+    io::Read,
     path::{Path, PathBuf},
 };
 
 use clap::{Parser, Subcommand};
 
-use crate::{Config, KeyRef};
+use crate::{Config, KeyRef, Layer};
 use is_terminal::IsTerminal as _;
 
-use crate::{ConfigValue, Configuration};
+use crate::ConfigValue;
 
-use super::{get_path, parse_key_ref};
-
-#[derive(clap::ValueEnum, Default, Clone)]
-pub enum Format {
-    #[value(name = "env")]
-    #[default]
-    EnvFile,
-    #[value(name = "json")]
-    Json,
-}
+use super::{
+    confirm, confirm_reveal, dotenv, format_config, get_path, is_default_config_path,
+    layer_project_file, parse_key_ref, resolve_project, Format, CONTEXT_ENV,
+};
 
 #[derive(Parser)]
 pub struct ConfigCLI {
-    /// Directory base, defaults to the base name of the current working directory,
-    /// e.g if the path is /home/joe/work/test-dir, cwd will be test-dir
+    /// Project id to use. Defaults to the id mapped with `config map`, the one in the nearest
+    /// `.secrets-manager` file, or the name of the git root or of the current directory
     #[arg(long)]
     cwd: Option<PathBuf>,
     /// keys can be in the format path.to.secret.key
@@ -41,26 +33,69 @@ enum ConfigCommands {
         /// if set, it will only return the specified key, if it exists.
         /// Key can be in the form of a `.` separated path
         key: Option<String>,
+        /// Print values coming from vaults in plain text instead of masking them
+        #[arg(long, default_value_t = false)]
+        reveal: bool,
     },
     /// Sets/adds the specified key to the current context
     /// value can either be `--value <hardcoded value> or --secret <secret key>`
     Set {
         /// Key can be in the form of a `.` separated path
         key: String,
+        /// Store the key in the .secrets-manager.json file of the project instead of the global config
+        #[arg(long, default_value_t = false)]
+        project: bool,
         #[command(subcommand)]
         value: ValueInput,
     },
     /// Deletes the specified key from the current context
-    Remove { key: String },
+    Remove {
+        key: String,
+        /// Remove the key from the .secrets-manager.json file of the project
+        #[arg(long, default_value_t = false)]
+        project: bool,
+    },
     ///Prints a tree structure of all keys for all bases
-    GetAll,
-    /// import from env file
-    Import { file: PathBuf },
-    /// Export config in either json or dotenv format
+    GetAll {
+        /// Print values coming from vaults in plain text instead of masking them
+        #[arg(long, default_value_t = false)]
+        reveal: bool,
+    },
+    /// import from env file, use `-` to read from stdin
+    Import {
+        file: PathBuf,
+        /// Only print the keys that would be imported
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+        /// Store the values in the specified vault and link the keys to them,
+        /// instead of saving them in plain text in the config
+        #[arg(long)]
+        as_secret: Option<String>,
+    },
+    /// Export config in the specified format
     Export {
         #[arg(short, long)]
         format: Format,
     },
+    /// Explain which config file, project and context are used from the current directory
+    Where,
+    /// Ask for confirmation before writing to or revealing the current context and the ones under it
+    Protect,
+    /// Stop protecting the current context
+    Unprotect,
+    /// Use a project id for a directory and its subdirectories
+    Map {
+        id: String,
+        /// Directory to map, defaults to the current directory
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+    /// Remove the project id mapped to a directory
+    Unmap {
+        /// Mapped directory, defaults to the current directory
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
 }
 
 #[derive(Subcommand)]
@@ -85,14 +120,29 @@ pub async fn handle_config(
     mut config: Config,
     cli: ConfigCLI,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    layer_project_file(&mut config, cli.cwd.clone())?;
     match cli.command {
-        ConfigCommands::Get { key } => {
+        ConfigCommands::Get { key, reveal } => {
             let path = get_path(&config, cli.cwd)?;
             let key = key.unwrap_or("".to_string());
             let key_ref = parse_key_ref(key.as_str(), &path)?;
-            print_config(&config, &key_ref)?;
+            if reveal {
+                let what = match key.as_str() {
+                    "" => String::from("all keys"),
+                    _ => key_ref.to_string(),
+                };
+                // the key, or the keys under it when it is not defined
+                let mut vaults = config.required_vaults(&key_ref.path, Some(&key_ref.key))?;
+                vaults.extend(config.required_vaults(&key_ref.path.join(&key_ref.key), None)?);
+                confirm_reveal(&config, &format!("Revealing {}", what), &path, &vaults)?;
+            }
+            print_config(&config, &key_ref, reveal).await?;
         }
-        ConfigCommands::Set { key, value } => {
+        ConfigCommands::Set {
+            key,
+            value,
+            project,
+        } => {
             let path = get_path(&config, cli.cwd)?;
             let key_ref = parse_key_ref(&key, &path)?;
             let value = match value {
@@ -102,56 +152,181 @@ pub async fn handle_config(
                 }
             };
             let display_key = key_ref.to_string();
-            if let Some(replaced) = config.set(key_ref, value)? {
+            if config.is_protected_path(&key_ref.path) {
+                // the value itself is not echoed, the message can end up in logs
+                let action = match value {
+                    ConfigValue::Secret(ref vault, _) => format!(
+                        "Setting {} to a secret of vault {} in context {}",
+                        key_ref,
+                        vault,
+                        path.display()
+                    ),
+                    ConfigValue::Value(_) => {
+                        format!("Setting {} in context {}", key_ref, path.display())
+                    }
+                };
+                confirm(&config, &action)?;
+            }
+            let layer = if project {
+                Layer::Project
+            } else {
+                Layer::Global
+            };
+            // values are never echoed, the output can end up in logs
+            if config.set_in(layer, key_ref, value).await?.is_some() {
                 println!(
-                    "{} value set successfully, previous value was {}",
-                    display_key, replaced
+                    "{} value set successfully, it replaced the previous one",
+                    display_key
                 );
             } else {
                 println!("{} value set successfully", display_key);
             }
             config.save().await?;
         }
-        ConfigCommands::Remove { key } => {
+        ConfigCommands::Remove { key, project } => {
             let path = get_path(&config, cli.cwd)?;
             let key_ref = parse_key_ref(&key, &path)?;
-            let Some(removed) = config.remove(&key_ref) else {
-                return Err(format!("{} not found", key_ref).into());
+            let layer = if project {
+                Layer::Project
+            } else {
+                Layer::Global
             };
+            if config.is_protected_path(&key_ref.path) {
+                confirm(
+                    &config,
+                    &format!("Removing {} from context {}", key_ref, path.display()),
+                )?;
+            }
+            if config.remove_from(layer, &key_ref)?.is_none() {
+                return Err(format!("{} not found", key_ref).into());
+            }
             config.save().await?;
-            println!(
-                "{} removed successfully, previous value was {}",
-                key_ref, removed
-            );
+            println!("{} removed successfully", key_ref);
         }
-        ConfigCommands::GetAll => {
-            println!("{}", config.display());
+        ConfigCommands::GetAll { reveal } => {
+            if reveal && config.has_protected() {
+                confirm(&config, "Revealing the keys of every context")?;
+            }
+            println!("{}", config.display(reveal).await?);
         }
-        ConfigCommands::Import { file } => {
+        ConfigCommands::Import {
+            file,
+            dry_run,
+            as_secret,
+        } => {
             let path = get_path(&config, cli.cwd)?;
-            import_config(config, &path, file).await?;
+            import_config(config, &path, file, dry_run, as_secret).await?;
         }
         ConfigCommands::Export { format } => {
             let path = get_path(&config, cli.cwd)?;
-            export_config(&config, &path, &format)?;
+            let vaults = config.required_vaults(&path, None)?;
+            confirm_reveal(&config, "Exporting the keys in plain text", &path, &vaults)?;
+            export_config(&config, &path, format).await?;
+        }
+        ConfigCommands::Protect => {
+            let path = get_path(&config, cli.cwd)?;
+            config.protect_context(path.clone(), true);
+            config.save().await?;
+            println!("Context {} is protected", path.display());
+        }
+        ConfigCommands::Unprotect => {
+            let path = get_path(&config, cli.cwd)?;
+            if !config.protect_context(path.clone(), false) {
+                Err(format!("Context {} is not protected", path.display()))?;
+            }
+            config.save().await?;
+            println!("Context {} is no longer protected", path.display());
+        }
+        ConfigCommands::Where => {
+            let source = if is_default_config_path(config.path()) {
+                "default location"
+            } else {
+                "set with --config-dir or --config-file"
+            };
+            println!("config file: {} ({})", config.path().display(), source);
+            let project = resolve_project(&config, cli.cwd.clone())?;
+            println!("project: {} ({})", project.id, project.source);
+            match config.project_file() {
+                Some(file) => println!(
+                    "project file: {} (global values override it at the same path)",
+                    file.display()
+                ),
+                None => println!("project file: none"),
+            }
+            let context = config.get_current_context();
+            let env = config.active_env(&project.id)?;
+            if let Some((name, _, overridden)) = env {
+                let source = if overridden { "--env" } else { "`env use`" };
+                println!("environment: {} (selected with {})", name, source);
+            }
+            if let Some(context) = config.context_override() {
+                println!(
+                    "context: {} (from --context or {})",
+                    context.display(),
+                    CONTEXT_ENV
+                );
+            } else if let Some((name, env, _)) = env {
+                println!(
+                    "context: {} (from environment {})",
+                    env.context.display(),
+                    name
+                );
+            } else if context.as_os_str().is_empty() {
+                println!("context: none");
+            } else {
+                println!("context: {} (set with `context set`)", context.display());
+            }
+            let path = get_path(&config, cli.cwd)?;
+            println!("keys are read from: {}", path.display());
+        }
+        ConfigCommands::Map { id, dir } => {
+            let dir = std::path::absolute(dir.map_or_else(std::env::current_dir, Ok)?)?;
+            println!("{} is now project {}", dir.display(), id);
+            config.map_project(dir, id);
+            config.save().await?;
+        }
+        ConfigCommands::Unmap { dir } => {
+            let dir = std::path::absolute(dir.map_or_else(std::env::current_dir, Ok)?)?;
+            match config.unmap_project(&dir) {
+                Some(id) => println!("{} is no longer project {}", dir.display(), id),
+                None => Err(format!("{} is not mapped", dir.display()))?,
+            }
+            config.save().await?;
         }
     }
     Ok(())
 }
 
-pub fn print_config(config: &Config, key: &KeyRef) -> Result<(), Box<dyn std::error::Error>> {
-    let Some(value) = config.get(key) else {
-        let data = config.get_all(&key.path.join(&key.key));
+pub async fn print_config(
+    config: &Config,
+    key: &KeyRef,
+    reveal: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // layers are only worth mentioning when the project has its own file
+    let layer = |key_ref: &KeyRef| match config.project_file() {
+        Some(_) => config
+            .layer_of(key_ref)
+            .map(|l| format!(" [{}]", l))
+            .unwrap_or_default(),
+        None => String::new(),
+    };
+    let Some(value) = config.get_for_display(key, reveal).await? else {
+        let path = key.path.join(&key.key);
+        let data = config.get_all_for_display(&path, reveal).await?;
         if !data.is_empty() {
-            for (key, value) in data {
-                println!("{}: {}", key, value);
+            for (name, value) in data {
+                let key_ref = KeyRef {
+                    path: path.clone(),
+                    key: name.to_string(),
+                };
+                println!("{}: {}{}", name, value, layer(&key_ref));
             }
         } else {
             return Err(format!("Missing key {}", key).into());
         }
         return Ok(());
     };
-    println!("{}: {}", key.key, value);
+    println!("{}: {}{}", key.key, value, layer(key));
     Ok(())
 }
 
@@ -159,63 +334,74 @@ pub async fn import_config(
     mut config: Config,
     path: &Path,
     file: PathBuf,
+    dry_run: bool,
+    as_secret: Option<String>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    if file == PathBuf::from("-") {
+    let mut input = String::new();
+    if file.as_os_str() == "-" {
         if std::io::stdin().is_terminal() {
             println!("Only available in non-interactive terminal");
             ::std::process::exit(2);
         }
-        read_from_env(
-            BufReader::new(std::io::stdin().lock()),
-            path,
-            &mut config.config,
-        )?;
+        std::io::stdin().lock().read_to_string(&mut input)?;
     } else {
-        read_from_env(BufReader::new(File::open(&file)?), path, &mut config.config)?;
+        input = std::fs::read_to_string(&file)?;
+    }
+    let entries = dotenv::parse(&input).map_err(|e| format!("{}: {}", file.display(), e))?;
+    if let Some(ref vault) = as_secret {
+        if !config.vault_exists(vault) {
+            return Err(crate::ConfigError::VaultNotFound(vault.to_string()).into());
+        }
+    }
+    if !dry_run {
+        let keys: Vec<_> = entries.iter().map(|e| e.key.as_str()).collect();
+        let mut action = format!(
+            "Importing {} into context {}",
+            keys.join(", "),
+            path.display()
+        );
+        let mut protected = config.is_protected_path(path);
+        if let Some(ref vault) = as_secret {
+            action.push_str(&format!(" and vault {}", vault));
+            protected |= config.is_protected_vault(vault);
+        }
+        if protected {
+            confirm(&config, &action)?;
+        }
+    }
+    for entry in entries {
+        let key_ref = parse_key_ref(&entry.key, path)?;
+        if dry_run {
+            match as_secret {
+                Some(ref vault) => println!("{} (secret in vault {})", key_ref, vault),
+                None => println!("{}", key_ref),
+            }
+            continue;
+        }
+        let value = match as_secret {
+            Some(ref vault) => {
+                config
+                    .set_secret(vault, key_ref.clone(), entry.value)
+                    .await?;
+                ConfigValue::Secret(vault.clone(), key_ref.clone())
+            }
+            None => ConfigValue::from_literal(&entry.value),
+        };
+        config.set(key_ref, value).await?;
+    }
+    if !dry_run {
+        config.save().await?;
     }
-    config.save().await?;
     Ok(())
 }
 
-pub fn export_config(
+pub async fn export_config(
     config: &Config,
     path: &Path,
-    format: &Format,
-) -> Result<(), serde_json::Error> {
-    let data = config.get_all(path);
-    let result = match format {
-        Format::EnvFile => export_as_env(&data),
-        Format::Json => serde_json::to_string(&data)?,
-    };
-    println!("{result}");
-    Ok(())
-}
-
-fn export_as_env(data: &HashMap<&str, String>) -> String {
-    let mut res = String::new();
-    for (key, value) in data {
-        res.push_str(&format!("{}=\"{}\"\n", key, value));
-    }
-    res
-}
-
-fn read_from_env(
-    buf: impl BufRead,
-    path: &Path,
-    config: &mut Configuration<ConfigValue>,
+    format: Format,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    buf.lines()
-        .filter_map(|line| line.ok())
-        .filter_map(|line| {
-            let remove_comments = &line[0..line.find("#").unwrap_or(line.len())];
-            let (key, value) = remove_comments.split_once('=')?;
-            let value = value.replace("\"", "");
-            let key = key.to_string();
-            Some((key, value))
-        })
-        .try_for_each(|(key, value)| {
-            let key_ref = parse_key_ref(&key, path)?;
-            config.set(key_ref, ConfigValue::Value(value));
-            Ok(())
-        })
+    let data = config.get_all(path).await?;
+    let result = format_config(&data, format)?;
+    print!("{result}");
+    Ok(())
 }
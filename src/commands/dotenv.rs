@@ -0,0 +1,272 @@
+use std::{iter::Peekable, str::Chars};
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+#[error("{message} at line {line}, column {column}")]
+pub struct DotenvError {
+    line: usize,
+    column: usize,
+    message: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+}
+
+/// Parses a dotenv file. Values are returned literally, variables are not expanded.
+///
+/// Supports `export` prefixes, comments, unquoted values, single quoted values (no escapes)
+/// and double quoted values with `\n`, `\r`, `\t`, `\"`, `\\` and `\$` escapes.
+/// Quoted values can span multiple lines.
+pub fn parse(input: &str) -> Result<Vec<Entry>, DotenvError> {
+    let mut lexer = Lexer::new(input);
+    let mut entries = Vec::new();
+    loop {
+        lexer.skip_blanks();
+        match lexer.peek() {
+            None => break,
+            Some('\n' | '\r') => {
+                lexer.next();
+                continue;
+            }
+            Some('#') => {
+                lexer.skip_comment();
+                continue;
+            }
+            _ => {}
+        }
+        let mut key = lexer.key()?;
+        if key == "export" && matches!(lexer.peek(), Some(' ' | '\t')) {
+            lexer.skip_blanks();
+            key = lexer.key()?;
+        }
+        lexer.skip_blanks();
+        lexer.expect('=')?;
+        lexer.skip_blanks();
+        let value = match lexer.peek() {
+            Some('\'') => lexer.single_quoted()?,
+            Some('"') => lexer.double_quoted()?,
+            _ => lexer.unquoted(),
+        };
+        lexer.end_of_line()?;
+        entries.push(Entry { key, value });
+    }
+    Ok(entries)
+}
+
+struct Lexer<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            chars: input
+                .strip_prefix('\u{feff}')
+                .unwrap_or(input)
+                .chars()
+                .peekable(),
+            line: 1,
+            column: 1,
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn error(&self, message: impl Into<String>) -> DotenvError {
+        self.error_at((self.line, self.column), message)
+    }
+
+    fn error_at(&self, (line, column): (usize, usize), message: impl Into<String>) -> DotenvError {
+        DotenvError {
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+
+    fn skip_blanks(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.next();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        while !matches!(self.peek(), None | Some('\n')) {
+            self.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), DotenvError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.next();
+                Ok(())
+            }
+            Some(c) => Err(self.error(format!("expected '{}', found '{}'", expected, c))),
+            None => Err(self.error(format!("expected '{}', found end of file", expected))),
+        }
+    }
+
+    fn key(&mut self) -> Result<String, DotenvError> {
+        let mut key = String::new();
+        match self.peek() {
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+            Some(c) => return Err(self.error(format!("expected a key, found '{}'", c))),
+            None => return Err(self.error("expected a key, found end of file")),
+        }
+        while let Some(c) = self.peek() {
+            if !(c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-')) {
+                break;
+            }
+            key.push(c);
+            self.next();
+        }
+        Ok(key)
+    }
+
+    fn unquoted(&mut self) -> String {
+        let mut value = String::new();
+        while let Some(c) = self.peek() {
+            // `#` only starts a comment at the beginning of the value or after a blank
+            let is_comment = c == '#' && (value.is_empty() || value.ends_with([' ', '\t']));
+            if matches!(c, '\n' | '\r') || is_comment {
+                break;
+            }
+            value.push(c);
+            self.next();
+        }
+        value.trim_end().to_string()
+    }
+
+    fn single_quoted(&mut self) -> Result<String, DotenvError> {
+        let start = (self.line, self.column);
+        self.next();
+        let mut value = String::new();
+        loop {
+            match self.next() {
+                Some('\'') => return Ok(value),
+                Some(c) => value.push(c),
+                None => return Err(self.error_at(start, "unterminated single quoted value")),
+            }
+        }
+    }
+
+    fn double_quoted(&mut self) -> Result<String, DotenvError> {
+        let start = (self.line, self.column);
+        self.next();
+        let mut value = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(value),
+                Some('\\') => match self.next() {
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some(c @ ('"' | '\\' | '$')) => value.push(c),
+                    Some(c) => {
+                        value.push('\\');
+                        value.push(c);
+                    }
+                    None => return Err(self.error_at(start, "unterminated double quoted value")),
+                },
+                Some(c) => value.push(c),
+                None => return Err(self.error_at(start, "unterminated double quoted value")),
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), DotenvError> {
+        self.skip_blanks();
+        if self.peek() == Some('#') {
+            self.skip_comment();
+        }
+        if self.peek() == Some('\r') {
+            self.next();
+        }
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.next();
+                Ok(())
+            }
+            Some(c) => Err(self.error(format!("unexpected character '{}'", c))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(key: &str, value: &str) -> Entry {
+        Entry {
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_values() {
+        let input = r#"
+# comment
+export A=plain value # trailing comment
+B="pa#ss\"word\n$HOME"
+C='single # "quoted" \n'
+D=
+E = spaced
+F=url#fragment
+G="multi
+line"
+"#;
+        assert_eq!(
+            parse(input).unwrap(),
+            vec![
+                entry("A", "plain value"),
+                entry("B", "pa#ss\"word\n$HOME"),
+                entry("C", "single # \"quoted\" \\n"),
+                entry("D", ""),
+                entry("E", "spaced"),
+                entry("F", "url#fragment"),
+                entry("G", "multi\nline"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_errors_report_position() {
+        assert_eq!(
+            parse("A=1\nB=\"open\nC=2").unwrap_err(),
+            DotenvError {
+                line: 2,
+                column: 3,
+                message: "unterminated double quoted value".to_string()
+            }
+        );
+        assert_eq!(
+            parse("A='x' y").unwrap_err(),
+            DotenvError {
+                line: 1,
+                column: 7,
+                message: "unexpected character 'y'".to_string()
+            }
+        );
+        assert_eq!(parse("1A=x").unwrap_err().column, 1);
+    }
+}
@@ -0,0 +1,102 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+use crate::{Config, Environment};
+
+use super::resolve_project;
+
+#[derive(Parser)]
+pub struct EnvCli {
+    /// Project id to use, defaults to the project of the current directory, see `config where`
+    #[arg(long)]
+    cwd: Option<PathBuf>,
+    #[command(subcommand)]
+    command: EnvCommands,
+}
+
+#[derive(Subcommand)]
+enum EnvCommands {
+    /// List the environments declared for the project, the current one is marked with `*`
+    List,
+    /// Use an environment for the following commands run in the project
+    Use { name: String },
+    /// Stop using the current environment, commands use the saved context and default vault again
+    Unuse,
+    /// Declare an environment for the project
+    Create {
+        name: String,
+        /// Context path of the environment under the project, defaults to its name
+        #[arg(long)]
+        context: Option<PathBuf>,
+        /// Vault used by `secret` commands in this environment when none is passed
+        #[arg(long)]
+        vault: Option<String>,
+        /// Mark the environment as protected, e.g for production
+        #[arg(long, default_value_t = false)]
+        protected: bool,
+    },
+}
+
+pub async fn handle_env(mut config: Config, cli: EnvCli) -> Result<(), Box<dyn std::error::Error>> {
+    let project = resolve_project(&config, cli.cwd)?;
+    match cli.command {
+        EnvCommands::List => {
+            let active = config.active_env(&project.id)?.map(|(name, _, _)| name);
+            for (name, env) in config.environments(&project.id).into_iter().flatten() {
+                let marker = if Some(name.as_str()) == active {
+                    "*"
+                } else {
+                    " "
+                };
+                let mut line = format!("{} {} -> {}", marker, name, env.context.display());
+                if let Some(ref vault) = env.vault {
+                    line.push_str(&format!(", vault {}", vault));
+                }
+                if env.protected {
+                    line.push_str(", protected");
+                }
+                println!("{}", line);
+            }
+        }
+        EnvCommands::Use { name } => {
+            config.use_env(&project.id, &name)?;
+            config.save().await?;
+            println!("Using environment {} in project {}", name, project.id);
+        }
+        EnvCommands::Unuse => match config.unuse_env(&project.id) {
+            Some(name) => {
+                config.save().await?;
+                println!(
+                    "Stopped using environment {} in project {}",
+                    name, project.id
+                );
+            }
+            None => Err(format!(
+                "No environment is in use in project {}",
+                project.id
+            ))?,
+        },
+        EnvCommands::Create {
+            name,
+            context,
+            vault,
+            protected,
+        } => {
+            if let Some(ref vault) = vault {
+                if !config.vault_exists(vault) {
+                    return Err(crate::ConfigError::VaultNotFound(vault.to_string()).into());
+                }
+            }
+            let env = Environment {
+                context: context.unwrap_or_else(|| PathBuf::from(&name)),
+                vault,
+                protected,
+            };
+            config.create_env(&project.id, name.clone(), env)?;
+            config.save().await?;
+            println!("Created environment {} in project {}", name, project.id);
+        }
+    }
+    Ok(())
+}
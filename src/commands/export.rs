@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+
+#[derive(clap::ValueEnum, Default, Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// dotenv file, values are double quoted and escaped
+    #[value(name = "env")]
+    #[default]
+    EnvFile,
+    #[value(name = "json")]
+    Json,
+    /// POSIX shell `export` statements, to be used with `eval` or `source`
+    #[value(name = "sh")]
+    Sh,
+    #[value(name = "fish")]
+    Fish,
+    #[value(name = "powershell")]
+    PowerShell,
+    /// file for `docker run --env-file`, values cannot contain new lines
+    #[value(name = "docker")]
+    Docker,
+    #[value(name = "yaml")]
+    Yaml,
+    #[value(name = "toml")]
+    Toml,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ExportError {
+    #[error(transparent)]
+    Encoding(#[from] serde_json::Error),
+    #[error("{0} is not a valid variable name for the {1:?} format")]
+    InvalidName(String, Format),
+    #[error("The value of {0} contains a new line, which is not supported by the {1:?} format")]
+    Multiline(String, Format),
+}
+
+/// Renders `data` in the specified format, escaping keys and values for the target
+pub fn format_config(data: &BTreeMap<&str, String>, format: Format) -> Result<String, ExportError> {
+    if format == Format::Json {
+        return Ok(serde_json::to_string(data)? + "\n");
+    }
+    let mut res = String::new();
+    for (key, value) in data {
+        let line = match format {
+            Format::EnvFile => format!("{}=\"{}\"", key, escape_double_quoted(value)),
+            Format::Sh => format!("export {}={}", shell_name(key, format)?, sh_quote(value)),
+            Format::Fish => format!("set -gx {} {}", shell_name(key, format)?, fish_quote(value)),
+            Format::PowerShell => format!(
+                "$env:{} = {}",
+                shell_name(key, format)?,
+                powershell_quote(value)
+            ),
+            Format::Docker => {
+                if key.contains(['=', '\n']) || key.is_empty() {
+                    return Err(ExportError::InvalidName(key.to_string(), format));
+                }
+                if value.contains(['\n', '\r']) {
+                    return Err(ExportError::Multiline(key.to_string(), format));
+                }
+                format!("{}={}", key, value)
+            }
+            // JSON strings are valid YAML double quoted scalars
+            Format::Yaml => format!(
+                "{}: {}",
+                serde_json::to_string(key)?,
+                serde_json::to_string(value)?
+            ),
+            Format::Toml => format!("{} = \"{}\"", toml_key(key), escape_toml(value)),
+            Format::Json => unreachable!(),
+        };
+        res.push_str(&line);
+        res.push('\n');
+    }
+    Ok(res)
+}
+
+fn shell_name(key: &str, format: Format) -> Result<&str, ExportError> {
+    let mut chars = key.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(key)
+    } else {
+        Err(ExportError::InvalidName(key.to_string(), format))
+    }
+}
+
+/// Escapes a value for a dotenv double quoted string, as read by `config import`
+fn escape_double_quoted(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => res.push_str("\\\\"),
+            '"' => res.push_str("\\\""),
+            '$' => res.push_str("\\$"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+/// Nothing is special inside single quotes, a quote is written by closing, escaping and reopening
+fn sh_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Inside fish single quotes only `\` and `'` need escaping
+fn fish_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// PowerShell single quoted strings are verbatim, quotes (including typographic ones) are doubled
+fn powershell_quote(value: &str) -> String {
+    let mut res = String::from("'");
+    for c in value.chars() {
+        if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}') {
+            res.push(c);
+        }
+        res.push(c);
+    }
+    res.push('\'');
+    res
+}
+
+fn toml_key(key: &str) -> String {
+    let bare = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'));
+    if bare {
+        key.to_string()
+    } else {
+        format!("\"{}\"", escape_toml(key))
+    }
+}
+
+fn escape_toml(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => res.push_str("\\\\"),
+            '"' => res.push_str("\\\""),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if c.is_control() => res.push_str(&format!("\\u{:04X}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::dotenv;
+
+    const VALUES: &[&str] = &[
+        "plain",
+        "with space # and hash",
+        "quote\" single' back\\slash",
+        "$HOME ${USER} $$",
+        "multi\nline\r\n\ttabbed",
+        "",
+    ];
+
+    fn data() -> BTreeMap<&'static str, String> {
+        VALUES
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (["A", "B", "C", "D", "E", "F"][i], v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn env_round_trip() {
+        let data = data();
+        let exported = format_config(&data, Format::EnvFile).unwrap();
+        let imported: BTreeMap<_, _> = dotenv::parse(&exported)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.key, e.value))
+            .collect();
+        for (key, value) in data {
+            assert_eq!(imported.get(key), Some(&value), "{key}");
+        }
+    }
+
+    #[test]
+    fn docker_values_are_verbatim() {
+        let data = BTreeMap::from([("A", String::from("x \"y\" # $z"))]);
+        assert_eq!(
+            format_config(&data, Format::Docker).unwrap(),
+            "A=x \"y\" # $z\n"
+        );
+        assert!(matches!(
+            format_config(&self::data(), Format::Docker),
+            Err(ExportError::Multiline(_, Format::Docker))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sh_round_trip() {
+        let data = data();
+        let exported = format_config(&data, Format::Sh).unwrap();
+        for (key, value) in data {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(format!("{}\nprintf %s \"${}\"", exported, key))
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), value, "{key}");
+        }
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(fish_quote("it's \\o/"), "'it\\'s \\\\o/'");
+        assert_eq!(powershell_quote("it's $x"), "'it''s $x'");
+        assert_eq!(toml_key("a.b"), "\"a.b\"");
+        assert_eq!(escape_toml("a\"\u{7f}"), "a\\\"\\u007F");
+        assert!(shell_name("not-valid", Format::Sh).is_err());
+    }
+}
@@ -1,20 +1,33 @@
 mod config;
+mod dotenv;
+mod env;
+mod export;
+mod project;
+mod run;
 mod secrets;
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap},
     path::{Path, PathBuf},
 };
 
 pub use config::*;
+pub use env::*;
+pub use export::*;
 use platform_dirs::AppDirs;
+pub use project::*;
+pub use run::*;
 pub use secrets::*;
 use serde::Deserialize;
 
 use crate::{
-    secrets::VaultTrait, AwsSecretVault, Config, ConfigFileData, ConfigValue, Configuration, KeyRef,
+    secrets::{AwsLayout, LazyVault},
+    AwsSecretVault, Config, ConfigFileData, ConfigValue, Configuration, Environment, KeyRef,
 };
 
+/// Environment variable overriding the saved context for one invocation, like `--context`
+pub const CONTEXT_ENV: &str = "SECRETS_MANAGER_CONTEXT";
+
 pub fn parse_key_ref(key: &str, path: &Path) -> Result<KeyRef, Box<dyn std::error::Error>> {
     let mut res: KeyRef = key.parse()?;
     if res.path.is_absolute() {
@@ -39,20 +52,23 @@ pub fn get_config_path(
     Ok(path)
 }
 
-pub fn init_config(config_file: impl AsRef<Path>) -> Result<(), std::io::Error> {
-    if config_file.as_ref().exists() {
-        Ok(())
-    } else {
-        std::fs::create_dir_all(
-            config_file
-                .as_ref()
-                .parent()
-                .expect("config file should have a parent dir"),
-        )?;
-        let file = std::fs::File::create(config_file.as_ref())?;
-        serde_json::to_writer_pretty(file, &ConfigFileData::default())?;
-        Ok(())
+pub async fn init_config(config_file: impl AsRef<Path>) -> Result<(), std::io::Error> {
+    let path = config_file.as_ref();
+    if path.exists() {
+        return Ok(());
+    }
+    std::fs::create_dir_all(path.parent().expect("config file should have a parent dir"))?;
+    let _lock = Config::lock(path).await?;
+    // another invocation may have created it while we were waiting for the lock
+    if !path.exists() {
+        ConfigFileData::default().write(path)?;
     }
+    Ok(())
+}
+
+/// Whether `path` is the config file used when neither --config-dir nor --config-file is passed
+pub fn is_default_config_path(path: &Path) -> bool {
+    get_config_path(None, None).is_ok_and(|default| default == path)
 }
 
 fn get_default_config_dir() -> Result<PathBuf, String> {
@@ -61,18 +77,136 @@ fn get_default_config_dir() -> Result<PathBuf, String> {
     Ok(app_dir.config_dir)
 }
 
-pub fn get_path(config: &Config, base: Option<PathBuf>) -> Result<PathBuf, std::io::Error> {
-    let cwd = base.map_or_else(|| std::env::current_dir(), Ok)?;
-    let path = &config.context;
+/// Layers the config file shared by the current project, if there is one, under the global config
+pub fn layer_project_file(
+    config: &mut Config,
+    base: Option<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let project = resolve_project(config, base)?;
+    if let Some(file) = project.config_file() {
+        config.load_project_file(file, &project.id)?;
+    }
+    Ok(())
+}
+
+/// Path of the config of the current project, in the context passed for this invocation,
+/// or the context of its environment if one is in use, or the saved one
+pub fn get_path(
+    config: &Config,
+    base: Option<PathBuf>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let project = resolve_project(config, base)?;
+    let path = effective_context(config, &project)?;
     let path = if path.is_absolute() {
         path.strip_prefix("/").unwrap()
     } else {
         path
     };
-    let cwd = PathBuf::from("/")
-        .join(PathBuf::from(cwd.file_name().unwrap_or_default()))
-        .join(path);
-    Ok(cwd)
+    Ok(PathBuf::from("/").join(project.id).join(path))
+}
+
+/// Warning for `context set` and `context reset`, when the saved context is not used in this
+/// shell because it pins another one, or by the project of the current directory because one
+/// of its environments is in use
+pub fn saved_context_warning(
+    config: &Config,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    if let Some(pinned) = std::env::var_os(CONTEXT_ENV) {
+        return Ok(Some(format!(
+            "{} is set to {}, e.g by `context shell`, commands run in this shell keep using it",
+            CONTEXT_ENV,
+            Path::new(&pinned).display()
+        )));
+    }
+    let project = resolve_project(config, None)?;
+    Ok(config.active_env(&project.id)?.map(|(name, _, _)| {
+        format!(
+            "environment {} is in use in project {}, its context is used instead of the saved one, see `env unuse`",
+            name, project.id
+        )
+    }))
+}
+
+/// Context used in `project`: the one passed for this invocation, or the context of its
+/// environment if one is in use, or the saved one
+pub fn effective_context<'a>(
+    config: &'a Config,
+    project: &Project,
+) -> Result<&'a Path, crate::ConfigError> {
+    Ok(
+        match (config.context_override(), config.active_env(&project.id)?) {
+            (Some(context), _) => context,
+            (None, Some((_, env, _))) => &env.context,
+            (None, None) => config.get_current_context(),
+        },
+    )
+}
+
+/// Asks before writing to or revealing a protected context or vault, `action` describes
+/// the resolved key and vault. Without a terminal, `--yes-i-mean-prod` is required
+pub fn confirm(config: &Config, action: &str) -> Result<(), Box<dyn std::error::Error>> {
+    use is_terminal::IsTerminal as _;
+    use std::io::Write as _;
+    if config.is_confirmed() {
+        return Ok(());
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(format!("{}: protected, pass --yes-i-mean-prod to confirm", action).into());
+    }
+    eprint!("{}: protected, continue? [y/N] ", action);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+        Ok(())
+    } else {
+        Err("Aborted".into())
+    }
+}
+
+/// Asks before revealing values of the context at `path` if it is protected, or if one of
+/// `vaults`, the vaults its values are read from, is protected
+pub fn confirm_reveal(
+    config: &Config,
+    action: &str,
+    path: &Path,
+    vaults: &BTreeSet<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let protected: Vec<&str> = vaults
+        .iter()
+        .filter(|name| config.is_protected_vault(name))
+        .map(String::as_str)
+        .collect();
+    if !config.is_protected_path(path) && protected.is_empty() {
+        return Ok(());
+    }
+    let mut action = format!("{} in context {}", action, path.display());
+    if !protected.is_empty() {
+        action.push_str(&format!(" from vault {}", protected.join(", ")));
+    }
+    confirm(config, &action)
+}
+
+/// Vault used when none is passed: the one of the current environment, then the default one
+pub fn get_vault_name(
+    config: &Config,
+    base: Option<PathBuf>,
+    name: Option<&str>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if name.is_none() {
+        let project = resolve_project(config, base)?;
+        if let Some((
+            _,
+            Environment {
+                vault: Some(vault), ..
+            },
+            _,
+        )) = config.active_env(&project.id)?
+        {
+            return Ok(vault.clone());
+        }
+    }
+    Ok(config.get_vault_name(name)?)
 }
 
 pub async fn handle_config_migration(
@@ -95,7 +229,8 @@ pub async fn handle_config_migration(
         .try_for_each(|key| -> Option<()> {
             let value = configuration.get(&key)?;
             let new_value = match value {
-                OldConfigValue::Value(v) => ConfigValue::from_value(v.clone()),
+                // old configs were not interpolated
+                OldConfigValue::Value(v) => ConfigValue::from_literal(v),
                 OldConfigValue::Secret { key, path } => ConfigValue::Secret(
                     secret_name.clone(),
                     KeyRef {
@@ -107,13 +242,15 @@ pub async fn handle_config_migration(
             new_config.set(key, new_value);
             Some(())
         })
-        .ok_or_else(|| format!("Could not migrate data, some keys are missing"))?;
-    let vault = AwsSecretVault::create(secret_name.to_string(), String::from("default")).await?;
-    let mut vaults = HashMap::new();
-    vaults.insert(
+        .ok_or_else(|| "Could not migrate data, some keys are missing".to_string())?;
+    let vault = AwsSecretVault::create(
         secret_name.to_string(),
-        Box::new(vault) as Box<dyn VaultTrait>,
-    );
+        String::from("default"),
+        AwsLayout::Single,
+    )
+    .await?;
+    let mut vaults = HashMap::new();
+    vaults.insert(secret_name.to_string(), LazyVault::loaded(Box::new(vault)));
     let config = Config {
         path: new_path.clone(),
         config: new_config,
@@ -121,6 +258,17 @@ pub async fn handle_config_migration(
         default_vault: Some(secret_name.to_string()),
         context: PathBuf::new(),
         updated: Vec::new(),
+        cache: None,
+        projects: BTreeMap::new(),
+        project_file: None,
+        environments: BTreeMap::new(),
+        env_override: None,
+        protected: Default::default(),
+        context_override: None,
+        confirmed: false,
+        offline: false,
+        verbose: false,
+        _lock: Config::lock(&new_path).await?,
     };
     config.save().await?;
     println!(
@@ -0,0 +1,326 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use crate::Config;
+
+/// File marking the root of a project, it may contain the id of the project
+pub const PROJECT_MARKER: &str = ".secrets-manager";
+/// Config shared by the project, also marks its root
+pub const PROJECT_FILE: &str = ".secrets-manager.json";
+
+/// Why a project id was chosen
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProjectSource {
+    /// Passed with `--cwd`
+    Argument,
+    /// Directory mapped with `config map`
+    Mapping(PathBuf),
+    /// Directory holding a `.secrets-manager` or `.secrets-manager.json` file
+    Marker(PathBuf),
+    /// Root of the git repository, the id is derived from the url of its remote
+    GitRemote(PathBuf, String),
+    /// Root of a git repository without remote, its name is used
+    GitRoot(PathBuf),
+    /// Root of a git repository with a remote, its name is used because config is stored
+    /// under it and none under the id derived from the remote, like before remotes were used
+    GitRootName(PathBuf, String),
+    /// Nothing found, the name of the current directory is used
+    CurrentDir(PathBuf),
+}
+
+impl Display for ProjectSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Argument => write!(f, "passed with --cwd"),
+            Self::Mapping(path) => write!(f, "{} is mapped to it", path.display()),
+            Self::Marker(path) => write!(f, "{} contains a project file", path.display()),
+            Self::GitRemote(path, url) => {
+                write!(f, "{} is the git root, with remote {}", path.display(), url)
+            }
+            Self::GitRoot(path) => write!(
+                f,
+                "{} is the git root, it has no remote so its name is used",
+                path.display()
+            ),
+            Self::GitRootName(path, id) => write!(
+                f,
+                "{} is the git root, its name is used as config is stored under it and not under {}",
+                path.display(),
+                id
+            ),
+            Self::CurrentDir(path) => write!(
+                f,
+                "no project root found above {}, using its name",
+                path.display()
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Project {
+    pub id: String,
+    pub source: ProjectSource,
+}
+
+impl Project {
+    /// Root directory of the project, unknown when the id is passed with `--cwd`
+    pub fn root(&self) -> Option<&Path> {
+        match self.source {
+            ProjectSource::Argument => None,
+            ProjectSource::Mapping(ref dir)
+            | ProjectSource::Marker(ref dir)
+            | ProjectSource::GitRemote(ref dir, _)
+            | ProjectSource::GitRoot(ref dir)
+            | ProjectSource::GitRootName(ref dir, _)
+            | ProjectSource::CurrentDir(ref dir) => Some(dir),
+        }
+    }
+
+    /// Config file shared by the project, it may not exist
+    pub fn config_file(&self) -> Option<PathBuf> {
+        self.root().map(|root| root.join(PROJECT_FILE))
+    }
+}
+
+/// Project of the current directory, or the one named by `base`
+pub fn resolve_project(config: &Config, base: Option<PathBuf>) -> Result<Project, std::io::Error> {
+    if let Some(base) = base {
+        return Ok(Project {
+            id: name_of(&base),
+            source: ProjectSource::Argument,
+        });
+    }
+    let cwd = std::env::current_dir()?;
+    let project = find_project(&cwd, config.projects());
+    let project = fall_back_to_root_name(project, |id| config.has_project_config(id));
+    // the project is resolved by several steps of a command, warn once
+    static WARNED: std::sync::Once = std::sync::Once::new();
+    match project.source {
+        ProjectSource::GitRootName(ref root, ref id) => WARNED.call_once(|| {
+            eprintln!(
+                "warning: using project id {} of {}, the id of git roots now comes from their remote. \
+                 Move its config to /{} or keep this id with `config map {} --dir {}`",
+                project.id,
+                root.display(),
+                id,
+                project.id,
+                root.display()
+            )
+        }),
+        ProjectSource::GitRoot(ref root) if config.is_verbose() => WARNED.call_once(|| {
+            eprintln!(
+                "warning: project id {} is the name of {}, other checkouts named {} share its config. \
+                 Add a remote or map the directory with `config map` to use another id",
+                project.id,
+                root.display(),
+                project.id
+            )
+        }),
+        _ => {}
+    }
+    Ok(project)
+}
+
+/// Projects found at a git root with a remote used the name of the root as id before, it is
+/// kept as long as `has_config` finds config stored under it and none under the new id
+fn fall_back_to_root_name(project: Project, has_config: impl Fn(&str) -> bool) -> Project {
+    let ProjectSource::GitRemote(ref root, _) = project.source else {
+        return project;
+    };
+    let name = name_of(root);
+    if name == project.id || has_config(&project.id) || !has_config(&name) {
+        return project;
+    }
+    Project {
+        id: name,
+        source: ProjectSource::GitRootName(root.clone(), project.id),
+    }
+}
+
+/// Looks for the nearest project root above `cwd`: a mapped directory first,
+/// then a `.secrets-manager` or `.secrets-manager.json` file, then the git root
+fn find_project(cwd: &Path, mappings: &BTreeMap<PathBuf, String>) -> Project {
+    let mapping = cwd
+        .ancestors()
+        .find_map(|dir| Some((dir, mappings.get(dir)?)));
+    if let Some((dir, id)) = mapping {
+        return Project {
+            id: id.clone(),
+            source: ProjectSource::Mapping(dir.to_path_buf()),
+        };
+    }
+    for dir in cwd.ancestors() {
+        let marker = dir.join(PROJECT_MARKER);
+        if marker.is_file() || dir.join(PROJECT_FILE).is_file() {
+            let content = std::fs::read_to_string(&marker).unwrap_or_default();
+            let id = content
+                .lines()
+                .map(|l| l.trim())
+                .find(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(|l| l.to_string())
+                .unwrap_or_else(|| name_of(dir));
+            return Project {
+                id,
+                source: ProjectSource::Marker(dir.to_path_buf()),
+            };
+        }
+    }
+    if let Some(dir) = cwd.ancestors().find(|dir| dir.join(".git").exists()) {
+        let remote = git_remote(dir).and_then(|url| Some((id_from_remote(&url)?, url)));
+        return match remote {
+            Some((id, url)) => Project {
+                id,
+                source: ProjectSource::GitRemote(dir.to_path_buf(), url),
+            },
+            None => Project {
+                id: name_of(dir),
+                source: ProjectSource::GitRoot(dir.to_path_buf()),
+            },
+        };
+    }
+    Project {
+        id: name_of(cwd),
+        source: ProjectSource::CurrentDir(cwd.to_path_buf()),
+    }
+}
+
+/// Url of the `origin` remote, or of the first one, of the repository at `root`
+fn git_remote(root: &Path) -> Option<String> {
+    let git = root.join(".git");
+    let git_dir = if git.is_file() {
+        // worktrees and submodules point to their git dir
+        let content = std::fs::read_to_string(&git).ok()?;
+        let dir = root.join(content.trim().strip_prefix("gitdir:")?.trim());
+        match std::fs::read_to_string(dir.join("commondir")) {
+            Ok(common) => dir.join(common.trim()),
+            Err(_) => dir,
+        }
+    } else {
+        git
+    };
+    let content = std::fs::read_to_string(git_dir.join("config")).ok()?;
+    let mut remotes = Vec::new();
+    let mut section = None;
+    for line in content.lines().map(|l| l.trim()) {
+        if line.starts_with('[') {
+            section = line
+                .strip_prefix("[remote \"")
+                .and_then(|l| l.strip_suffix("\"]"));
+        } else if let (Some(name), Some((key, url))) = (section, line.split_once('=')) {
+            if key.trim() == "url" {
+                remotes.push((name, url.trim()));
+            }
+        }
+    }
+    let (_, url) = remotes
+        .iter()
+        .find(|(name, _)| *name == "origin")
+        .or(remotes.first())?;
+    Some(url.to_string())
+}
+
+/// Path of the repository in a remote url, without its host, e.g `acme-api` for
+/// `git@github.com:acme/api.git`. Ids are a single part of a key, so `/` and `.` are replaced
+fn id_from_remote(url: &str) -> Option<String> {
+    let path = match url.split_once("://") {
+        Some((_, rest)) => rest.split_once('/').map_or("", |(_, path)| path),
+        // scp like syntax, or a local path
+        None => url.split_once(':').map_or(url, |(_, path)| path),
+    };
+    let path = path.trim_end_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+    let id = path
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+        .replace(
+            |c: char| !c.is_ascii_alphanumeric() && c != '-' && c != '_',
+            "-",
+        );
+    (!id.is_empty()).then_some(id)
+}
+
+fn name_of(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_root_wins() {
+        let dir =
+            std::env::temp_dir().join(format!("secrets-manager-project-{}", std::process::id()));
+        let repo = dir.join("checkout");
+        let nested = repo.join("api").join("src");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::create_dir_all(repo.join(".git")).unwrap();
+        let mut mappings = BTreeMap::new();
+
+        let project = find_project(&nested, &mappings);
+        assert_eq!(project.id, "checkout");
+        assert_eq!(project.source, ProjectSource::GitRoot(repo.clone()));
+        // checkouts of the same repository share their id, whatever their directory
+        let url = "git@github.com:acme/api.git";
+        std::fs::write(
+            repo.join(".git").join("config"),
+            format!("[core]\n\tbare = false\n[remote \"origin\"]\n\turl = {url}\n"),
+        )
+        .unwrap();
+        let project = find_project(&nested, &mappings);
+        assert_eq!(project.id, "acme-api");
+        assert_eq!(
+            project.source,
+            ProjectSource::GitRemote(repo.clone(), url.to_string())
+        );
+        // config stored under the name of the root before remotes were used is still found
+        let legacy = fall_back_to_root_name(project.clone(), |id| id == "checkout");
+        assert_eq!(legacy.id, "checkout");
+        assert_eq!(
+            legacy.source,
+            ProjectSource::GitRootName(repo.clone(), String::from("acme-api"))
+        );
+        let both = fall_back_to_root_name(project.clone(), |_| true);
+        assert_eq!(both, project);
+        assert_eq!(fall_back_to_root_name(project.clone(), |_| false), project);
+
+        std::fs::write(repo.join("api").join(PROJECT_MARKER), "").unwrap();
+        assert_eq!(find_project(&nested, &mappings).id, "api");
+        std::fs::write(repo.join("api").join(PROJECT_MARKER), "# id\nbilling-api\n").unwrap();
+        assert_eq!(find_project(&nested, &mappings).id, "billing-api");
+
+        mappings.insert(repo.clone(), String::from("mapped"));
+        let project = find_project(&nested, &mappings);
+        assert_eq!(project.id, "mapped");
+        assert_eq!(project.source, ProjectSource::Mapping(repo));
+
+        let project = find_project(&dir, &mappings);
+        assert_eq!(project.source, ProjectSource::CurrentDir(dir.clone()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ids_from_remote_urls() {
+        for (url, id) in [
+            ("git@github.com:acme/api.git", "acme-api"),
+            ("https://github.com/acme/api", "acme-api"),
+            (
+                "ssh://git@gitlab.com:2222/acme/backend/api.git/",
+                "acme-backend-api",
+            ),
+            ("/srv/git/api.v2.git", "srv-git-api-v2"),
+        ] {
+            assert_eq!(id_from_remote(url).as_deref(), Some(id), "{url}");
+        }
+        assert_eq!(id_from_remote("https://github.com"), None);
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+};
+
+use clap::Parser;
+use tokio::process::{Child, Command};
+
+use crate::Config;
+
+use super::{confirm_reveal, get_path, layer_project_file, CONTEXT_ENV};
+
+/// Variables inherited by the child when `--clean-env` is passed
+const MINIMAL_ENV: &[&str] = &["PATH", "HOME", "USER", "SHELL", "TERM", "LANG", "TMPDIR"];
+
+#[derive(Parser)]
+pub struct RunCli {
+    /// Project id to use, defaults to the project of the current directory, see `config where`
+    #[arg(long)]
+    cwd: Option<PathBuf>,
+    /// Only pass the resolved config and a minimal set of variables (PATH, HOME, ...)
+    /// to the command instead of the whole inherited environment
+    #[arg(long, default_value_t = false)]
+    clean_env: bool,
+    /// Command to run followed by its arguments, e.g `secrets-manager run -- npm start`
+    #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<String>,
+}
+
+/// Runs the command with the config of the current context as its environment
+/// and returns the exit code that should be used by this process.
+pub async fn handle_run(
+    mut config: Config,
+    cli: RunCli,
+) -> Result<i32, Box<dyn std::error::Error>> {
+    layer_project_file(&mut config, cli.cwd.clone())?;
+    let path = get_path(&config, cli.cwd)?;
+    let vaults = config.required_vaults(&path, None)?;
+    let action = format!("Passing the keys to {}", cli.command.join(" "));
+    confirm_reveal(&config, &action, &path, &vaults)?;
+    let env: Vec<(String, String)> = config
+        .get_all(&path)
+        .await?
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect();
+    // release the config lock, the child can run for a long time
+    drop(config);
+    let mut child = command(&cli.command, cli.clean_env, env)?;
+    let status = wait_forwarding_signals(&mut child).await?;
+    Ok(exit_code(status))
+}
+
+/// Spawns `argv` with `env` on top of the inherited environment, or of the minimal one
+fn command(
+    argv: &[String],
+    clean_env: bool,
+    env: Vec<(String, String)>,
+) -> Result<Child, Box<dyn std::error::Error>> {
+    let (program, args) = argv
+        .split_first()
+        .ok_or_else(|| String::from("Missing command to run"))?;
+    let mut command = Command::new(program);
+    command.args(args);
+    if clean_env {
+        command.env_clear();
+        for var in MINIMAL_ENV {
+            if let Some(value) = std::env::var_os(var) {
+                command.env(var, value);
+            }
+        }
+    }
+    command.envs(env);
+    let child = command
+        .spawn()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    Ok(child)
+}
+
+/// Starts an interactive shell where every command uses `context`, through SECRETS_MANAGER_CONTEXT,
+/// and returns its exit code
+pub async fn handle_context_shell(
+    config: Config,
+    context: PathBuf,
+) -> Result<i32, Box<dyn std::error::Error>> {
+    // release the config lock, commands run in the shell need it
+    drop(config);
+    let shell = std::env::var_os("SHELL").unwrap_or_else(|| "sh".into());
+    eprintln!(
+        "Context {} pinned in this shell, exit it to go back",
+        context.display()
+    );
+    let mut child = shell_command(&shell, &context)
+        .spawn()
+        .map_err(|e| format!("Failed to run {}: {}", shell.to_string_lossy(), e))?;
+    let status = wait_forwarding_signals(&mut child).await?;
+    Ok(exit_code(status))
+}
+
+/// `shell` with `context` pinned for the commands it runs
+fn shell_command(shell: &OsStr, context: &Path) -> Command {
+    let mut command = Command::new(shell);
+    command.env(CONTEXT_ENV, context);
+    command
+}
+
+#[cfg(unix)]
+async fn wait_forwarding_signals(child: &mut Child) -> std::io::Result<ExitStatus> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    // every signal is caught so that it does not kill us before the child exits
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut quit = signal(SignalKind::quit())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut user1 = signal(SignalKind::user_defined1())?;
+    let mut user2 = signal(SignalKind::user_defined2())?;
+    loop {
+        let forwarded = tokio::select! {
+            status = child.wait() => return status,
+            _ = interrupt.recv() => unless_from_terminal(libc::SIGINT),
+            _ = quit.recv() => unless_from_terminal(libc::SIGQUIT),
+            _ = terminate.recv() => Some(libc::SIGTERM),
+            _ = hangup.recv() => Some(libc::SIGHUP),
+            _ = user1.recv() => Some(libc::SIGUSR1),
+            _ = user2.recv() => Some(libc::SIGUSR2),
+        };
+        if let (Some(signal), Some(pid)) = (forwarded, child.id()) {
+            // SAFETY: kill has no memory safety requirements, pid belongs to our child
+            unsafe {
+                libc::kill(pid as libc::pid_t, signal);
+            }
+        }
+    }
+}
+
+/// SIGINT and SIGQUIT typed in the terminal already reach the child, which is in our process
+/// group. They are only forwarded when that group is not in the foreground, e.g when they
+/// are sent with `kill` to a process run by a supervisor or as PID 1 of a container
+#[cfg(unix)]
+fn unless_from_terminal(signal: libc::c_int) -> Option<libc::c_int> {
+    // SAFETY: tcgetpgrp and getpgrp have no memory safety requirements
+    let foreground = unsafe { libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp() };
+    (!foreground).then_some(signal)
+}
+
+#[cfg(not(unix))]
+async fn wait_forwarding_signals(child: &mut Child) -> std::io::Result<ExitStatus> {
+    loop {
+        tokio::select! {
+            status = child.wait() => return status,
+            // the console delivers ctrl-c to the child as well
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+}
+
+fn exit_code(status: ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            // same convention as shells for processes killed by a signal
+            return 128 + signal;
+        }
+    }
+    1
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::process::Stdio;
+
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    use super::*;
+
+    fn sh(script: &str) -> Vec<String> {
+        ["sh", "-c", script].map(String::from).to_vec()
+    }
+
+    async fn run(script: &str, clean_env: bool, env: Vec<(String, String)>) -> i32 {
+        let mut child = command(&sh(script), clean_env, env).unwrap();
+        exit_code(wait_forwarding_signals(&mut child).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn exit_code_is_passed_through() {
+        assert_eq!(run("exit 3", false, Vec::new()).await, 3);
+        assert_eq!(run("kill -KILL $$", false, Vec::new()).await, 128 + 9);
+    }
+
+    #[tokio::test]
+    async fn clean_env_only_keeps_config_and_minimal_vars() {
+        let (inherited, _) = std::env::vars()
+            .find(|(key, _)| !MINIMAL_ENV.contains(&key.as_str()))
+            .expect("the test runs with some variables");
+        let env = vec![(String::from("FROM_CONFIG"), String::from("1"))];
+        let script = format!(r#"test "$FROM_CONFIG" = 1 && test -n "${{{inherited}+x}}""#);
+        assert_eq!(run(&script, false, env.clone()).await, 0);
+        let script = format!(
+            r#"test "$FROM_CONFIG" = 1 && test -z "${{{inherited}+x}}" && test -n "$PATH""#
+        );
+        assert_eq!(run(&script, true, env).await, 0);
+    }
+
+    #[tokio::test]
+    async fn context_shell_pins_the_context() {
+        let script = format!(r#"test "${}" = staging/eu && exit 4"#, CONTEXT_ENV);
+        let mut child = shell_command(OsStr::new("sh"), Path::new("staging/eu"))
+            .args(["-c", &script])
+            .spawn()
+            .unwrap();
+        let code = exit_code(wait_forwarding_signals(&mut child).await.unwrap());
+        assert_eq!(code, 4);
+    }
+
+    #[tokio::test]
+    async fn signals_are_forwarded_to_the_child() {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut signals = vec![(SignalKind::terminate(), libc::SIGTERM, "TERM")];
+        // interrupts are left to the terminal when the tests run in its foreground
+        if unless_from_terminal(libc::SIGINT).is_some() {
+            signals.push((SignalKind::interrupt(), libc::SIGINT, "INT"));
+            signals.push((SignalKind::quit(), libc::SIGQUIT, "QUIT"));
+        }
+        for (kind, signal_number, name) in signals {
+            // keeps the default action of the signal, killing the test process, out of the way
+            let _guard = signal(kind).unwrap();
+            let script = format!("trap 'exit 7' {name}; echo ready; while :; do sleep 0.1; done");
+            let mut child = Command::new("sh")
+                .args(["-c", &script])
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+            assert_eq!(stdout.next_line().await.unwrap().unwrap(), "ready");
+            let wait = tokio::spawn(async move { wait_forwarding_signals(&mut child).await });
+            // the handlers are installed once the task runs, signal until the child is gone
+            let status = loop {
+                // SAFETY: kill has no memory safety requirements
+                unsafe {
+                    libc::kill(libc::getpid(), signal_number);
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                if wait.is_finished() {
+                    break wait.await.unwrap().unwrap();
+                }
+            };
+            assert_eq!(exit_code(status), 7, "{name}");
+        }
+    }
+}
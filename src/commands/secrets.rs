@@ -1,12 +1,25 @@
 use clap::{Parser, Subcommand};
 
-use crate::{secrets::VaultTrait, AwsSecretVault, Config};
+use std::path::PathBuf;
+
+use crate::{
+    secrets::{AwsLayout, CacheSettings, VaultKind, VaultTrait},
+    AgeFileVault, AwsSecretVault, AwsSsmVault, Config, ConflictPolicy, ExecVault, HashicorpVault,
+    KeyRef, PasswordStoreVault, SecureStoreVault, SopsVault,
+};
+
+use super::{confirm, get_vault_name};
 
 #[derive(Parser)]
 pub struct VaultCli {
-    /// Name of the vault to use. If not specified the default one will be used
+    /// Name of the vault to use. If not specified the vault of the current environment,
+    /// or the default one, will be used
     #[arg(name = "--vault")]
     vault_name: Option<String>,
+    /// Project id to use for the vault of its environment, defaults to the project of the
+    /// current directory, see `config where`
+    #[arg(long)]
+    cwd: Option<PathBuf>,
     #[command(subcommand)]
     command: VaultCommands,
 }
@@ -42,8 +55,71 @@ enum VaultCommands {
     },
     /// Set the specified vault as default
     SetDefault,
+    /// Ask for confirmation before writing to or revealing the secrets of the vault
+    Protect,
+    /// Stop protecting the vault
+    Unprotect,
     /// Prints a tree with all secrets contained in the specified vault
     GetAll,
+    /// List the versions of an AWS Secrets Manager vault with the keys changed by each of them
+    History {
+        /// Only list the versions where this key changed
+        key: Option<String>,
+    },
+    /// Write the content of a previous version of an AWS Secrets Manager vault as its current version
+    Rollback {
+        /// Id of the version to restore, see `secret history`
+        #[arg(long)]
+        version: String,
+    },
+    /// Manage who can decrypt an age file vault
+    Recipients {
+        #[command(subcommand)]
+        command: RecipientCommands,
+    },
+    /// Encrypt an age file vault again for its current recipients, with a new file key
+    Reencrypt,
+    /// Copy the secrets of a vault, or of a path in it, to another vault
+    Copy {
+        /// Vault to copy from
+        #[arg(long)]
+        from: String,
+        /// Vault to copy to, keys keep the same path
+        #[arg(long)]
+        to: String,
+        /// `.` separated path of the secrets to copy, everything if omitted
+        path: Option<String>,
+        /// What to do with keys that already exist in the destination with another value
+        #[arg(long, value_enum, default_value_t)]
+        on_conflict: ConflictPolicy,
+        /// Point the config values referencing the copied secrets to the destination vault
+        #[arg(long, default_value_t = false)]
+        rewrite_refs: bool,
+    },
+    /// Fetch vaults from their backends, bypassing the cache, and store them in the cache.
+    /// All vaults are refreshed unless a vault is specified
+    Refresh,
+    /// Configure the local cache of vault contents, also used by --offline.
+    /// Prints the current settings if no option is passed
+    Cache {
+        /// Seconds a cached vault is used before being fetched again
+        #[arg(long, conflicts_with = "disable")]
+        ttl: Option<u64>,
+        /// Disable the cache and delete the cached vaults
+        #[arg(long, default_value_t = false)]
+        disable: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum RecipientCommands {
+    /// List the public keys the vault is encrypted to
+    List,
+    /// Add an age or SSH public key and re-encrypt the vault
+    Add { recipient: String },
+    /// Remove a public key and re-encrypt the vault.
+    /// Secrets it could read should be rotated, older copies of the file stay readable to it
+    Remove { recipient: String },
 }
 
 #[derive(clap::Subcommand)]
@@ -51,10 +127,102 @@ enum SecretProvider {
     /// Use AWS secret manager as a provider
     #[command(name = "--aws")]
     AwsSecretManager {
+        /// Name of the secret, or the name prefix with --per-key
         secret_name: String,
         /// Aws profile to use
         #[arg(long, default_value_t = String::from("default"))]
         profile: String,
+        /// Store each key in its own secret named <SECRET_NAME>/<PATH>/<KEY>
+        #[arg(long, default_value_t = false)]
+        per_key: bool,
+    },
+    /// Use AWS SSM Parameter Store as a provider, keys are stored as SecureString parameters
+    #[command(name = "--ssm")]
+    AwsSsm {
+        /// Parameter hierarchy holding the configuration, e.g /app
+        prefix: String,
+        /// Aws profile to use
+        #[arg(long, default_value_t = String::from("default"))]
+        profile: String,
+    },
+    /// Use a file encrypted to a list of age or SSH public keys as a provider,
+    /// it can be committed to the project repository
+    #[command(name = "--age")]
+    AgeFile {
+        /// Path of the vault file, it will be created if it does not exist
+        path: PathBuf,
+        /// Public keys the file is encrypted to, required to create a new file
+        #[arg(long = "recipient")]
+        recipients: Vec<String>,
+        /// age identity file or SSH private key used to decrypt, defaults to
+        /// SECRETS_MANAGER_AGE_IDENTITY, then ~/.ssh/id_ed25519 or ~/.ssh/id_rsa
+        #[arg(long)]
+        identity: Option<PathBuf>,
+    },
+    /// Use a SOPS encrypted YAML or JSON file as a provider, nested maps are paths.
+    /// The data key is decrypted with SOPS_AGE_KEY, SOPS_AGE_KEY_FILE or gpg
+    #[command(name = "--sops")]
+    Sops {
+        /// Path of the file, .yaml/.yml files are written as YAML and others as JSON.
+        /// It will be created if it does not exist
+        path: PathBuf,
+        /// age public keys the data key is encrypted to, when creating the file
+        #[arg(long = "age-recipient")]
+        age_recipients: Vec<String>,
+        /// PGP fingerprints the data key is encrypted to, when creating the file
+        #[arg(long = "pgp")]
+        pgp_fingerprints: Vec<String>,
+    },
+    /// Use a local encrypted SecureStore file as a provider
+    #[command(name = "--securestore")]
+    SecureStore {
+        /// Path of the store file, it will be created if it does not exist
+        path: PathBuf,
+        /// Key file used to unlock the store, a new key is generated if the file does not exist.
+        /// If omitted, the password is read from SECRETS_MANAGER_SECURESTORE_PASSWORD
+        #[arg(long)]
+        key_file: Option<PathBuf>,
+    },
+    /// Use an external executable speaking the JSON plugin protocol as a provider,
+    /// e.g to wrap the 1Password CLI or gopass
+    #[command(name = "--exec")]
+    Exec {
+        /// Executable of the plugin
+        command: String,
+        /// Arguments passed to the plugin on every call
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Use a `pass` password store as a provider, entries are read and written with gpg
+    #[command(name = "--pass")]
+    PasswordStore {
+        /// Folder of the store holding the entries, the whole store if omitted
+        #[arg(default_value = "")]
+        prefix: PathBuf,
+        /// Root of the store, defaults to PASSWORD_STORE_DIR or ~/.password-store
+        #[arg(long)]
+        store_dir: Option<PathBuf>,
+    },
+    /// Use a HashiCorp Vault KV v2 engine as a provider.
+    /// Authenticates with VAULT_TOKEN (or ~/.vault-token), or with AppRole if --role-id is passed
+    #[command(name = "--hashicorp")]
+    HashicorpVault {
+        /// Address of the Vault server, e.g https://vault.example.com:8200
+        address: String,
+        /// Path inside the mount under which the secrets are stored
+        path: String,
+        /// Mount point of the KV v2 secrets engine
+        #[arg(long, default_value_t = String::from("secret"))]
+        mount: String,
+        /// Vault Enterprise namespace
+        #[arg(long)]
+        namespace: Option<String>,
+        /// AppRole role id, the secret id is read from VAULT_SECRET_ID
+        #[arg(long)]
+        role_id: Option<String>,
+        /// Mount point of the AppRole auth method
+        #[arg(long, default_value_t = String::from("approle"))]
+        approle_mount: String,
     },
 }
 
@@ -70,25 +238,110 @@ pub async fn handle_secrets(
         } => {
             handle_create_secret(config, name, provider, set_default).await?;
         }
+        VaultCommands::Copy {
+            from,
+            to,
+            path,
+            on_conflict,
+            rewrite_refs,
+        } => {
+            if from == to {
+                Err("Cannot copy a vault onto itself")?;
+            }
+            // copying reveals the secrets of `from` to whoever can read `to`
+            if config.is_protected_vault(&from) || config.is_protected_vault(&to) {
+                let what = path.as_deref().unwrap_or("all secrets");
+                confirm(
+                    &config,
+                    &format!("Copying {} from vault {} to vault {}", what, from, to),
+                )?;
+            }
+            let path = match path {
+                Some(path) => {
+                    let key_ref: KeyRef = path.parse()?;
+                    key_ref.path.join(key_ref.key)
+                }
+                None => PathBuf::from("/"),
+            };
+            let report = config.copy_secrets(&from, &to, &path, on_conflict).await?;
+            for key_ref in &report.copied {
+                println!("Copied {}", key_ref);
+            }
+            for key_ref in &report.conflicts {
+                println!("Skipped {}, it has another value in {}", key_ref, to);
+            }
+            if rewrite_refs {
+                for key_ref in config.rewrite_secret_refs(&from, &to, &report) {
+                    println!("{} now references {}", key_ref, to);
+                }
+            }
+            config.save().await?;
+        }
+        VaultCommands::Refresh => {
+            let names = match cli.vault_name {
+                Some(name) => vec![name],
+                None => config.vault_names(),
+            };
+            for (name, version) in config.refresh(&names).await? {
+                match version {
+                    Some(version) => println!("Refreshed {} at version {}", name, version),
+                    None => println!("Refreshed {}", name),
+                }
+            }
+            config.save().await?;
+        }
+        VaultCommands::Cache { ttl, disable } => {
+            if disable {
+                config.set_cache(None)?;
+                config.save().await?;
+                println!("Cache disabled");
+            } else if let Some(ttl) = ttl {
+                config.set_cache(Some(CacheSettings { ttl }))?;
+                config.save().await?;
+                println!("Vaults are cached for {} seconds", ttl);
+            } else {
+                match config.cache_settings() {
+                    Some(settings) => println!("Vaults are cached for {} seconds", settings.ttl),
+                    None => println!("Cache disabled"),
+                }
+            }
+        }
         update_commands => {
-            let vault_name = config.get_vault_name(cli.vault_name.as_deref())?;
+            let vault_name = get_vault_name(&config, cli.cwd, cli.vault_name.as_deref())?;
+            let protected = config.is_protected_vault(&vault_name);
             match update_commands {
                 VaultCommands::Set { key, value } => {
-                    let key_ref = key.parse()?;
-                    let replaced = config.set_secret(&vault_name, key_ref, value)?;
+                    let key_ref: KeyRef = key.parse()?;
+                    if protected {
+                        confirm(
+                            &config,
+                            &format!("Setting {} in vault {}", key_ref, vault_name),
+                        )?;
+                    }
+                    let replaced = config.set_secret(&vault_name, key_ref, value).await?;
                     config.save().await?;
-                    if let Some(replaced) = replaced {
-                        println!("Set value for {}, previous value was {}", key, replaced);
+                    // values are never echoed, the output can end up in logs
+                    if replaced.is_some() {
+                        println!("Set value for {}, it replaced the previous one", key);
+                    } else {
+                        println!("Set value for {}", key);
                     }
                 }
                 VaultCommands::Get { key } => {
-                    let key_ref = key.parse()?;
-                    let value = config.get_secret(&vault_name, &key_ref)?;
+                    let key_ref: KeyRef = key.parse()?;
+                    if protected {
+                        confirm(
+                            &config,
+                            &format!("Revealing {} from vault {}", key_ref, vault_name),
+                        )?;
+                    }
+                    let value = config.get_secret(&vault_name, &key_ref).await?;
                     if let Some(value) = value {
                         println!("{value}");
                     } else {
                         let data = config
-                            .get_all_secrets(&&vault_name, &key_ref.path.join(key_ref.key))?;
+                            .get_all_secrets(&vault_name, &key_ref.path.join(key_ref.key))
+                            .await?;
                         if !data.is_empty() {
                             for (key, value) in data {
                                 println!("{}: {}", key, value);
@@ -99,20 +352,117 @@ pub async fn handle_secrets(
                     }
                 }
                 VaultCommands::Remove { key } => {
-                    let key_ref = key.parse()?;
-                    let replaced = config.remove_secret(&vault_name, &key_ref)?;
-                    if let Some(replaced) = replaced {
+                    let key_ref: KeyRef = key.parse()?;
+                    if protected {
+                        confirm(
+                            &config,
+                            &format!("Removing {} from vault {}", key_ref, vault_name),
+                        )?;
+                    }
+                    let removed = config.remove_secret(&vault_name, &key_ref).await?;
+                    if removed.is_some() {
                         config.save().await?;
-                        println!("Removed {}, value was {}", key, replaced);
+                        println!("Removed {}", key);
                     } else {
                         Err(format!("{} not found", key))?;
                     }
                 }
+                VaultCommands::Protect => {
+                    config.protect_vault(&vault_name, true)?;
+                    config.save().await?;
+                    println!("Vault {} is protected", vault_name);
+                }
+                VaultCommands::Unprotect => {
+                    if !config.protect_vault(&vault_name, false)? {
+                        Err(format!("Vault {} is not protected", vault_name))?;
+                    }
+                    config.save().await?;
+                    println!("Vault {} is no longer protected", vault_name);
+                }
                 VaultCommands::SetDefault => {
                     config.set_default_vault(vault_name);
                 }
                 VaultCommands::GetAll => {
-                    println!("{}", config.display_vault(&vault_name)?);
+                    if protected {
+                        confirm(
+                            &config,
+                            &format!("Revealing all secrets of vault {}", vault_name),
+                        )?;
+                    }
+                    println!("{}", config.display_vault(&vault_name).await?);
+                }
+                VaultCommands::History { key } => {
+                    let key_ref: Option<KeyRef> = key.map(|k| k.parse()).transpose()?;
+                    let vault = aws_vault(&config, &vault_name).await?;
+                    for version in vault.history(key_ref.as_ref()).await? {
+                        let changed: Vec<String> =
+                            version.changed.iter().map(|k| k.to_string()).collect();
+                        println!(
+                            "{} {} {}",
+                            version.id,
+                            version.created.as_deref().unwrap_or("-"),
+                            version.stages.join(",")
+                        );
+                        println!("  changed: {}", changed.join(", "));
+                    }
+                }
+                VaultCommands::Rollback { version } => {
+                    if protected {
+                        let action =
+                            format!("Restoring version {} of vault {}", version, vault_name);
+                        confirm(&config, &action)?;
+                    }
+                    let mut vault = aws_vault(&config, &vault_name).await?;
+                    let new_version = vault.rollback(&version).await?;
+                    println!(
+                        "Restored version {} of {} as version {}",
+                        version, vault_name, new_version
+                    );
+                    config.replace_vault(&vault_name, Box::new(vault))?;
+                    config.save().await?;
+                }
+                VaultCommands::Recipients { command } => {
+                    let mut vault = age_vault(&config, &vault_name)?;
+                    match command {
+                        RecipientCommands::List => {
+                            for recipient in vault.recipients() {
+                                println!("{}", recipient);
+                            }
+                        }
+                        RecipientCommands::Add { recipient } => {
+                            // the vault is only written once confirmed
+                            if !vault.add_recipient(recipient.clone())? {
+                                Err(format!("{} is already a recipient", recipient))?;
+                            }
+                            if protected {
+                                let action = format!(
+                                    "Adding recipient {} to vault {}",
+                                    recipient, vault_name
+                                );
+                                confirm(&config, &action)?;
+                            }
+                            vault.reencrypt()?;
+                            println!("Recipient added, {} re-encrypted", vault_name);
+                        }
+                        RecipientCommands::Remove { recipient } => {
+                            vault.remove_recipient(&recipient)?;
+                            if protected {
+                                let action = format!(
+                                    "Removing recipient {} from vault {}",
+                                    recipient, vault_name
+                                );
+                                confirm(&config, &action)?;
+                            }
+                            vault.reencrypt()?;
+                            println!("Recipient removed, {} re-encrypted", vault_name);
+                        }
+                    }
+                }
+                VaultCommands::Reencrypt => {
+                    if protected {
+                        confirm(&config, &format!("Re-encrypting vault {}", vault_name))?;
+                    }
+                    age_vault(&config, &vault_name)?.reencrypt()?;
                 }
                 _ => unreachable!(),
             }
@@ -131,15 +481,88 @@ async fn handle_create_secret(
         SecretProvider::AwsSecretManager {
             secret_name,
             profile,
+            per_key,
         } => {
             println!(
                 "Creating vault {} with AWS Secrets Manager and secret name {}",
                 name, secret_name
             );
-            let vault = AwsSecretVault::create(secret_name, profile).await?;
-            config
-                .add_vault(name.clone(), vault.into_vault_kind())
-                .await?;
+            let layout = if per_key {
+                AwsLayout::PerKey
+            } else {
+                AwsLayout::Single
+            };
+            let vault = AwsSecretVault::create(secret_name, profile, layout).await?;
+            config.add_vault(name.clone(), vault.to_vault_kind())?;
+        }
+        SecretProvider::AwsSsm { prefix, profile } => {
+            println!(
+                "Creating vault {} with AWS SSM Parameter Store under {}",
+                name, prefix
+            );
+            let vault = AwsSsmVault::create(prefix, profile).await?;
+            config.add_vault(name.clone(), vault.to_vault_kind())?;
+        }
+        SecretProvider::Exec { command, args } => {
+            println!("Creating vault {} with plugin {}", name, command);
+            let vault = ExecVault::from_info(&ExecVault::info(command, args)).await?;
+            config.add_vault(name.clone(), vault.to_vault_kind())?;
+        }
+        SecretProvider::PasswordStore { prefix, store_dir } => {
+            println!("Creating vault {} with password store", name);
+            let info = PasswordStoreVault::info(store_dir, prefix)?;
+            let vault = PasswordStoreVault::from_info(&info).await?;
+            config.add_vault(name.clone(), vault.to_vault_kind())?;
+        }
+        SecretProvider::AgeFile {
+            path,
+            recipients,
+            identity,
+        } => {
+            println!("Creating vault {} with age file {}", name, path.display());
+            let vault = AgeFileVault::create(path, identity, recipients)?;
+            config.add_vault(name.clone(), vault.to_vault_kind())?;
+        }
+        SecretProvider::Sops {
+            path,
+            age_recipients,
+            pgp_fingerprints,
+        } => {
+            println!("Creating vault {} with SOPS file {}", name, path.display());
+            let vault = SopsVault::create(path, age_recipients, pgp_fingerprints).await?;
+            config.add_vault(name.clone(), vault.to_vault_kind())?;
+        }
+        SecretProvider::SecureStore { path, key_file } => {
+            println!(
+                "Creating vault {} with SecureStore file {}",
+                name,
+                path.display()
+            );
+            let vault = SecureStoreVault::create(path, key_file)?;
+            config.add_vault(name.clone(), vault.to_vault_kind())?;
+        }
+        SecretProvider::HashicorpVault {
+            address,
+            path,
+            mount,
+            namespace,
+            role_id,
+            approle_mount,
+        } => {
+            println!(
+                "Creating vault {} with HashiCorp Vault {} at {}/{}",
+                name, address, mount, path
+            );
+            let info = HashicorpVault::info(
+                address,
+                mount,
+                path,
+                namespace,
+                role_id.map(|id| (id, approle_mount)),
+            );
+            // check that the server is reachable and the credentials are valid
+            let vault = HashicorpVault::from_info(&info).await?;
+            config.add_vault(name.clone(), vault.to_vault_kind())?;
         }
     }
     if set_default {
@@ -148,3 +571,23 @@ async fn handle_create_secret(
     config.save().await?;
     Ok(())
 }
+
+async fn aws_vault(
+    config: &Config,
+    name: &str,
+) -> Result<AwsSecretVault, Box<dyn std::error::Error>> {
+    if config.is_offline() {
+        return Err(format!("Vault {} cannot be reached while offline", name).into());
+    }
+    match config.vault_kind(name)? {
+        VaultKind::AwsSecretManager(info) => Ok(AwsSecretVault::from_info(info).await?),
+        _ => Err(format!("Vault {} is not an AWS Secrets Manager vault", name).into()),
+    }
+}
+
+fn age_vault(config: &Config, name: &str) -> Result<AgeFileVault, Box<dyn std::error::Error>> {
+    match config.vault_kind(name)? {
+        VaultKind::AgeFile(info) => Ok(AgeFileVault::from_info(info)?),
+        _ => Err(format!("Vault {} is not an age file vault", name).into()),
+    }
+}
@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::BTreeMap,
     path::{Path, PathBuf},
 };
 
@@ -7,16 +7,16 @@ use serde::{Deserialize, Serialize};
 
 use crate::KeyRef;
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct Configuration<T> {
     #[serde(flatten)]
-    data: HashMap<PathBuf, HashMap<String, T>>,
+    data: BTreeMap<PathBuf, BTreeMap<String, T>>,
 }
 
 impl<T> Configuration<T> {
     pub fn new() -> Self {
         Self {
-            data: HashMap::new(),
+            data: BTreeMap::new(),
         }
     }
 
@@ -37,7 +37,7 @@ impl<T> Configuration<T> {
             })
     }
 
-    pub fn get_all(&self, path: impl AsRef<Path>) -> HashMap<&String, &T> {
+    pub fn get_all(&self, path: impl AsRef<Path>) -> BTreeMap<&String, &T> {
         let paths: Vec<_> = path.as_ref().ancestors().collect();
         paths
             .into_iter()
@@ -46,7 +46,7 @@ impl<T> Configuration<T> {
                 let res = self.data.get(path);
                 res
             })
-            .fold(HashMap::new(), |mut acc, values| {
+            .fold(BTreeMap::new(), |mut acc, values| {
                 acc.extend(values.iter());
                 acc
             })
@@ -76,6 +76,76 @@ impl<T> Configuration<T> {
     pub fn is_empty(&self) -> bool {
         self.data.is_empty()
     }
+
+    /// Iterates over the paths and the keys stored directly under them
+    pub fn sections(&self) -> impl Iterator<Item = (&Path, &BTreeMap<String, T>)> {
+        self.data
+            .iter()
+            .map(|(path, values)| (path.as_path(), values))
+    }
+
+    /// Keys stored directly under `path`
+    pub(crate) fn section(&self, path: &Path) -> Option<&BTreeMap<String, T>> {
+        self.data.get(path)
+    }
+
+    /// Gets the value stored exactly at `key_ref`, without looking into parent paths
+    pub(crate) fn get_exact(&self, key_ref: &KeyRef) -> Option<&T> {
+        self.data
+            .get(&key_ref.path)
+            .and_then(|map| map.get(&key_ref.key))
+    }
+}
+
+impl<T> Configuration<T>
+where
+    T: Clone + PartialEq,
+{
+    /// Three-way merge of the local edits in `self` with the edits in `theirs`, both made on top of `base`.
+    /// Fails with the list of keys that were changed differently on both sides.
+    pub fn merge(&self, base: &Self, theirs: &Self) -> Result<Self, Vec<KeyRef>> {
+        let mut keys: Vec<KeyRef> = Vec::new();
+        for key in base.keys("/").chain(self.keys("/")).chain(theirs.keys("/")) {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        let mut res = Configuration::new();
+        let mut conflicts = Vec::new();
+        for key in keys {
+            let base_value = base.get_exact(&key);
+            let ours = self.get_exact(&key);
+            let their_value = theirs.get_exact(&key);
+            let value = if ours == their_value || base_value == their_value {
+                ours
+            } else if base_value == ours {
+                their_value
+            } else {
+                conflicts.push(key);
+                continue;
+            };
+            if let Some(value) = value {
+                res.set(key, value.clone());
+            }
+        }
+        if conflicts.is_empty() {
+            Ok(res)
+        } else {
+            Err(conflicts)
+        }
+    }
+
+    /// Keys added, removed or modified in `self` compared to `other`
+    pub fn changed_keys(&self, other: &Self) -> Vec<KeyRef> {
+        let mut keys: Vec<KeyRef> = Vec::new();
+        for key in other.keys("/").chain(self.keys("/")) {
+            if !keys.contains(&key) && self.get_exact(&key) != other.get_exact(&key) {
+                keys.push(key);
+            }
+        }
+        keys.sort_by_key(|k| k.to_string());
+        keys
+    }
 }
 
 impl<T> Configuration<T>
@@ -111,6 +181,9 @@ where
                 current_path = new_path;
             }
         }
+        if tree.arena.is_empty() {
+            return String::new();
+        }
         self.print_node(&tree, 0, "")
     }
 
@@ -210,7 +283,7 @@ impl<T: PartialEq> ArenaTree<T> {
 #[derive(Default)]
 struct PathData<'a, T: Default> {
     path: PathBuf,
-    data: Option<&'a HashMap<String, T>>,
+    data: Option<&'a BTreeMap<String, T>>,
 }
 
 impl<'a, T: Default> PartialEq for PathData<'a, T> {
@@ -222,40 +295,44 @@ impl<'a, T: Default> PartialEq for PathData<'a, T> {
 #[cfg(test)]
 mod tests {
 
-    use std::path::Path;
-
     use super::*;
 
-    fn get_config(cwd: impl AsRef<Path>) -> Configuration<String> {
+    fn get_config() -> Configuration<String> {
         Configuration {
-            cwd: PathBuf::from(cwd.as_ref()),
-            data: HashMap::from_iter([
+            data: BTreeMap::from_iter([
                 (
                     PathBuf::from("/"),
-                    HashMap::from_iter([
+                    BTreeMap::from_iter([
                         ("foo".into(), "bar1".into()),
                         ("fem".into(), "is_great".into()),
                     ]),
                 ),
                 (
                     PathBuf::from("/foo"),
-                    HashMap::from_iter([("foo".into(), "bar2".into())]),
+                    BTreeMap::from_iter([("foo".into(), "bar2".into())]),
                 ),
                 (
                     PathBuf::from("/foo/bar"),
-                    HashMap::from_iter([("foo".into(), "bar3".into())]),
+                    BTreeMap::from_iter([("foo".into(), "bar3".into())]),
                 ),
             ]),
         }
     }
 
+    fn key_ref(path: &str, key: &str) -> KeyRef {
+        KeyRef {
+            path: PathBuf::from(path),
+            key: key.to_string(),
+        }
+    }
+
     #[test]
     fn get_all_values() {
-        let config = get_config("/foo/bar");
-        let all_values = config.get_values_for_cwd();
+        let config = get_config();
+        let all_values = config.get_all("/foo/bar");
         assert_eq!(
             all_values,
-            HashMap::from_iter([
+            BTreeMap::from_iter([
                 (&String::from("foo"), &String::from("bar3")),
                 (&String::from("fem"), &String::from("is_great"))
             ])
@@ -264,30 +341,115 @@ mod tests {
 
     #[test]
     fn add_value() {
-        let mut config = get_config("/foo/bar");
-        config.set("uri", "foo");
-        let result = config.get_value("uri").unwrap();
+        let mut config = get_config();
+        config.set(key_ref("/foo/bar", "uri"), "foo");
+        let result = config.get(&key_ref("/foo/bar", "uri")).unwrap();
         assert_eq!(result, "foo");
     }
 
     #[test]
     fn get_value() {
-        let config = get_config("/foo/bar");
-        assert_eq!(config.get_value("foo"), Some(&String::from("bar3")));
-        assert_eq!(config.get_value("fem"), Some(&String::from("is_great")));
+        let config = get_config();
+        assert_eq!(
+            config.get(&key_ref("/foo/bar", "foo")),
+            Some(&String::from("bar3"))
+        );
+        assert_eq!(
+            config.get(&key_ref("/foo/bar", "fem")),
+            Some(&String::from("is_great"))
+        );
+        assert_eq!(
+            config.get(&key_ref("/foo", "foo")),
+            Some(&String::from("bar2"))
+        );
+        assert_eq!(
+            config.get(&key_ref("/", "foo")),
+            Some(&String::from("bar1"))
+        );
+    }
+
+    #[test]
+    fn merge_disjoint_edits() {
+        let base = get_config();
+        let mut ours = base.clone();
+        ours.set(key_ref("/foo", "foo"), "ours");
+        ours.remove(&key_ref("/", "fem"));
+        let mut theirs = base.clone();
+        theirs.set(key_ref("/foo/bar", "new"), "theirs");
+
+        let merged = ours.merge(&base, &theirs).unwrap();
+        assert_eq!(
+            merged.get(&key_ref("/foo", "foo")),
+            Some(&String::from("ours"))
+        );
+        assert_eq!(
+            merged.get(&key_ref("/foo/bar", "new")),
+            Some(&String::from("theirs"))
+        );
+        assert_eq!(merged.get(&key_ref("/", "fem")), None);
+    }
+
+    #[test]
+    fn merge_conflicting_edits() {
+        let base = get_config();
+        let mut ours = base.clone();
+        ours.set(key_ref("/foo", "foo"), "ours");
+        ours.set(key_ref("/", "same"), "value");
+        let mut theirs = base.clone();
+        theirs.set(key_ref("/foo", "foo"), "theirs");
+        theirs.set(key_ref("/", "same"), "value");
 
-        let config = config.with_path("/foo");
-        assert_eq!(config.get_value("foo"), Some(&String::from("bar2")));
+        let conflicts = ours.merge(&base, &theirs).unwrap_err();
+        assert_eq!(conflicts, vec![key_ref("/foo", "foo")]);
+    }
 
-        let config = config.with_path("/");
-        assert_eq!(config.get_value("foo"), Some(&String::from("bar1")));
+    #[test]
+    fn changed_keys() {
+        let old = get_config();
+        let mut new = old.clone();
+        new.set(key_ref("/foo", "foo"), "changed");
+        new.set(key_ref("/", "added"), "value");
+        new.remove(&key_ref("/", "fem"));
+        assert_eq!(
+            new.changed_keys(&old),
+            vec![
+                key_ref("/", "added"),
+                key_ref("/", "fem"),
+                key_ref("/foo", "foo")
+            ]
+        );
+        assert!(old.changed_keys(&old).is_empty());
+    }
+
+    #[test]
+    fn display_is_sorted() {
+        let mut config = get_config();
+        config.set(key_ref("/foo", "a"), "first");
+        config.set(key_ref("/bar", "z"), "last");
+        assert_eq!(
+            config.display(),
+            "/\n\
+             \u{251C}\u{2500}fem: is_great\n\
+             \u{251C}\u{2500}foo: bar1\n\
+             \u{251C}\u{2500}bar\n\
+             \u{2502} \u{2514}\u{2500}z: last\n\
+             \u{2514}\u{2500}foo\n  \
+             \u{251C}\u{2500}a: first\n  \
+             \u{251C}\u{2500}foo: bar2\n  \
+             \u{2514}\u{2500}bar\n    \
+             \u{2514}\u{2500}foo: bar3\n"
+        );
+        assert_eq!(Configuration::<String>::new().display(), "");
     }
 
     #[test]
     fn remove_value() {
-        let mut config = get_config("/foo/bar");
-        let res = config.remove_value("foo").unwrap();
+        let mut config = get_config();
+        let res = config.remove(&key_ref("/foo/bar", "foo")).unwrap();
         assert_eq!(res, String::from("bar3"));
-        assert_eq!(config.get_value("foo"), Some(&String::from("bar2")));
+        assert_eq!(
+            config.get(&key_ref("/foo/bar", "foo")),
+            Some(&String::from("bar2"))
+        );
     }
 }
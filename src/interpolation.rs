@@ -0,0 +1,323 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::{ConfigError, ConfigValue, KeyRef};
+
+/// Piece of a config value, references are written as `${KEY}` or `${vault::path.to.key}`
+/// and `$$` is a literal `$`
+#[derive(Debug, PartialEq)]
+pub(crate) enum Token<'a> {
+    Text(&'a str),
+    Key(&'a str),
+    Secret(&'a str, KeyRef),
+}
+
+/// Splits `value` into text and references, returns `None` if a reference is not closed
+pub(crate) fn tokenize(value: &str) -> Option<Vec<Token<'_>>> {
+    let mut tokens = Vec::new();
+    let mut rest = value;
+    while let Some(pos) = rest.find('$') {
+        if pos > 0 {
+            tokens.push(Token::Text(&rest[..pos]));
+        }
+        let tail = &rest[pos + 1..];
+        if let Some(tail) = tail.strip_prefix('$') {
+            tokens.push(Token::Text("$"));
+            rest = tail;
+        } else if let Some(tail) = tail.strip_prefix('{') {
+            let end = tail.find('}')?;
+            let reference = tail[..end].trim();
+            match reference.split_once("::") {
+                Some((vault, key)) => tokens.push(Token::Secret(vault, key.parse().ok()?)),
+                None => tokens.push(Token::Key(reference)),
+            }
+            rest = &tail[end + 1..];
+        } else {
+            // a lone `$` is kept as is
+            tokens.push(Token::Text("$"));
+            rest = tail;
+        }
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Text(rest));
+    }
+    Some(tokens)
+}
+
+/// Resolves the values of a single context, expanding references to sibling keys and vault secrets
+pub(crate) struct Resolver<'a, F> {
+    values: HashMap<&'a str, &'a ConfigValue>,
+    resolve_secret: F,
+    /// resolved values, flagged if they contain a value read from a vault
+    resolved: HashMap<&'a str, (String, bool)>,
+    stack: Vec<&'a str>,
+    /// Fail on secrets missing from their vault instead of keeping the reference
+    strict: bool,
+}
+
+impl<'a, F> Resolver<'a, F>
+where
+    F: Fn(&str, &KeyRef) -> Option<&'a str>,
+{
+    pub fn new(values: BTreeMap<&'a String, &'a ConfigValue>, resolve_secret: F) -> Self {
+        Self {
+            values: values.into_iter().map(|(k, v)| (k.as_str(), v)).collect(),
+            resolve_secret,
+            resolved: HashMap::new(),
+            stack: Vec::new(),
+            strict: false,
+        }
+    }
+
+    /// Values are about to be used, not displayed: a missing secret is an error
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.values.keys().copied()
+    }
+
+    /// Stored, unresolved, value of `key`
+    pub fn definition(&self, key: &str) -> Option<&'a ConfigValue> {
+        self.values.get(key).copied()
+    }
+
+    /// Whether the resolved value of `key` contains a secret read from a vault
+    pub fn is_sensitive(&self, key: &str) -> bool {
+        self.resolved
+            .get(key)
+            .is_some_and(|(_, sensitive)| *sensitive)
+    }
+
+    /// Names of the vaults needed to resolve `keys`, following references
+    pub fn required_vaults<'k>(
+        &self,
+        keys: impl IntoIterator<Item = &'k str>,
+    ) -> Result<HashSet<&'a str>, ConfigError> {
+        let secrets = self.required_secrets(keys)?;
+        Ok(secrets.into_iter().map(|(name, _)| name).collect())
+    }
+
+    /// Vault secrets needed to resolve `keys`, following references
+    pub fn required_secrets<'k>(
+        &self,
+        keys: impl IntoIterator<Item = &'k str>,
+    ) -> Result<Vec<(&'a str, KeyRef)>, ConfigError> {
+        let mut secrets = Vec::new();
+        let mut add = |name: &'a str, key_ref: &KeyRef| {
+            if !secrets.iter().any(|(n, k)| *n == name && k == key_ref) {
+                secrets.push((name, key_ref.clone()));
+            }
+        };
+        let mut seen = HashSet::new();
+        let mut pending: Vec<&str> = keys.into_iter().collect();
+        while let Some(key) = pending.pop() {
+            let Some((&key, value)) = self.values.get_key_value(key) else {
+                continue;
+            };
+            if !seen.insert(key) {
+                continue;
+            }
+            match value {
+                ConfigValue::Secret(name, key_ref) => add(name.as_str(), key_ref),
+                ConfigValue::Value(value) => {
+                    for token in Self::tokens(key, value)? {
+                        match token {
+                            Token::Key(key) => pending.push(key),
+                            Token::Secret(name, key_ref) => add(name, &key_ref),
+                            Token::Text(_) => {}
+                        }
+                    }
+                }
+            }
+        }
+        Ok(secrets)
+    }
+
+    /// Resolves `key`, vaults returned by [`Resolver::required_vaults`] must be loaded
+    pub fn resolve(&mut self, key: &str) -> Result<Option<String>, ConfigError> {
+        Ok(self.resolve_flagged(key)?.map(|(value, _)| value))
+    }
+
+    fn resolve_flagged(&mut self, key: &str) -> Result<Option<(String, bool)>, ConfigError> {
+        let Some((&key, &value)) = self.values.get_key_value(key) else {
+            return Ok(None);
+        };
+        if let Some(value) = self.resolved.get(key) {
+            return Ok(Some(value.clone()));
+        }
+        if let Some(pos) = self.stack.iter().position(|k| *k == key) {
+            let mut cycle = self.stack[pos..].to_vec();
+            cycle.push(key);
+            return Err(ConfigError::InterpolationCycle(cycle.join(" -> ")));
+        }
+        let res = match value {
+            ConfigValue::Secret(name, key_ref) => match (self.resolve_secret)(name, key_ref) {
+                Some(secret) => (secret.to_string(), true),
+                None if self.strict => {
+                    return Err(ConfigError::SecretNotFound(
+                        name.to_string(),
+                        key_ref.to_string(),
+                    ))
+                }
+                // missing secrets are displayed as references
+                None => (value.to_string(), false),
+            },
+            ConfigValue::Value(value) => {
+                // popped before any error is returned, the resolver is reused for other keys
+                self.stack.push(key);
+                let res = self.expand(key, value);
+                self.stack.pop();
+                res?
+            }
+        };
+        self.resolved.insert(key, res.clone());
+        Ok(Some(res))
+    }
+
+    /// Value of `key`, `value` with its references resolved
+    fn expand(&mut self, key: &str, value: &'a str) -> Result<(String, bool), ConfigError> {
+        let mut res = String::new();
+        let mut sensitive = false;
+        for token in Self::tokens(key, value)? {
+            match token {
+                Token::Text(text) => res.push_str(text),
+                Token::Key(reference) => {
+                    let (value, is_secret) = self.resolve_flagged(reference)?.ok_or_else(|| {
+                        ConfigError::UnresolvedReference(reference.to_string(), key.to_string())
+                    })?;
+                    res.push_str(&value);
+                    sensitive |= is_secret;
+                }
+                Token::Secret(name, key_ref) => match (self.resolve_secret)(name, &key_ref) {
+                    Some(secret) => {
+                        res.push_str(secret);
+                        sensitive = true;
+                    }
+                    None if self.strict => {
+                        return Err(ConfigError::SecretNotFound(
+                            name.to_string(),
+                            key_ref.to_string(),
+                        ))
+                    }
+                    // like missing secret values, missing references are displayed as such
+                    None => res.push_str(&format!("${{{}::{}}}", name, key_ref)),
+                },
+            }
+        }
+        Ok((res, sensitive))
+    }
+
+    fn tokens(key: &str, value: &'a str) -> Result<Vec<Token<'a>>, ConfigError> {
+        tokenize(value).ok_or_else(|| ConfigError::InvalidReference(key.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(entries: &[(&str, &str)]) -> Vec<(String, ConfigValue)> {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), ConfigValue::Value(v.to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn tokenize_references_and_escapes() {
+        assert_eq!(
+            tokenize("a${B}$${C}${v::db.pass}$").unwrap(),
+            vec![
+                Token::Text("a"),
+                Token::Key("B"),
+                Token::Text("$"),
+                Token::Text("{C}"),
+                Token::Secret("v", "db.pass".parse().unwrap()),
+                Token::Text("$"),
+            ]
+        );
+        assert_eq!(tokenize("${B"), None);
+    }
+
+    #[test]
+    fn resolve_nested_references() {
+        let entries = values(&[
+            ("USER", "joe"),
+            ("HOST", "${USER}.local"),
+            ("URL", "pg://${USER}:${vault::db.pass}@${HOST}"),
+        ]);
+        let map = entries.iter().map(|(k, v)| (k, v)).collect();
+        let mut resolver = Resolver::new(map, |name: &str, key_ref: &KeyRef| {
+            (name == "vault" && key_ref.to_string() == "db.pass").then_some("secret")
+        });
+        assert_eq!(
+            resolver.required_vaults(["URL"]).unwrap(),
+            HashSet::from(["vault"])
+        );
+        assert_eq!(
+            resolver.required_secrets(["URL", "HOST"]).unwrap(),
+            [("vault", "db.pass".parse().unwrap())]
+        );
+        assert_eq!(
+            resolver.resolve("URL").unwrap().unwrap(),
+            "pg://joe:secret@joe.local"
+        );
+        assert!(resolver.is_sensitive("URL"));
+        assert!(!resolver.is_sensitive("HOST"));
+    }
+
+    #[test]
+    fn resolve_detects_cycles() {
+        let entries = values(&[("A", "${B}"), ("B", "x${A}")]);
+        let map = entries.iter().map(|(k, v)| (k, v)).collect();
+        let mut resolver = Resolver::new(map, |_: &str, _: &KeyRef| None);
+        let err = resolver.resolve("A").unwrap_err();
+        assert!(matches!(err, ConfigError::InterpolationCycle(cycle) if cycle == "A -> B -> A"));
+    }
+
+    #[test]
+    fn failed_keys_are_not_reported_as_cycles() {
+        let entries = values(&[("A", "${MISSING}"), ("B", "x${A}")]);
+        let map = entries.iter().map(|(k, v)| (k, v)).collect();
+        let mut resolver = Resolver::new(map, |_: &str, _: &KeyRef| None);
+        for key in ["A", "B"] {
+            let err = resolver.resolve(key).unwrap_err();
+            assert!(
+                matches!(err, ConfigError::UnresolvedReference(ref missing, ref by) if missing == "MISSING" && by == "A"),
+                "{key}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn strict_resolve_fails_on_missing_secrets() {
+        let value = ConfigValue::Secret(String::from("vault"), "db.pass".parse().unwrap());
+        let key = String::from("PASS");
+        let map: BTreeMap<_, _> = [(&key, &value)].into();
+        let mut resolver = Resolver::new(map.clone(), |_: &str, _: &KeyRef| None);
+        assert_eq!(
+            resolver.resolve("PASS").unwrap().unwrap(),
+            "secret [vault::/db/pass]"
+        );
+        let mut resolver = Resolver::new(map, |_: &str, _: &KeyRef| None).strict(true);
+        let err = resolver.resolve("PASS").unwrap_err();
+        assert!(
+            matches!(err, ConfigError::SecretNotFound(vault, key) if vault == "vault" && key == "db.pass")
+        );
+
+        // references inside values behave the same
+        let entries = values(&[("URL", "pg://${vault::db.pass}@host")]);
+        let map: BTreeMap<_, _> = entries.iter().map(|(k, v)| (k, v)).collect();
+        let mut resolver = Resolver::new(map.clone(), |_: &str, _: &KeyRef| None);
+        assert_eq!(
+            resolver.resolve("URL").unwrap().unwrap(),
+            "pg://${vault::db.pass}@host"
+        );
+        assert!(!resolver.is_sensitive("URL"));
+        let mut resolver = Resolver::new(map, |_: &str, _: &KeyRef| None).strict(true);
+        let err = resolver.resolve("URL").unwrap_err();
+        assert!(matches!(err, ConfigError::SecretNotFound(..)));
+    }
+}
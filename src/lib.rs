@@ -1,18 +1,39 @@
 pub mod commands;
 mod config;
+mod interpolation;
 mod secrets;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ffi::OsString,
     fmt::Display,
+    fs::{File, OpenOptions},
+    io::Write,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
 pub use config::Configuration;
-pub use secrets::AwsSecretVault;
-use secrets::{VaultKind, VaultTrait};
+use hmac::{Hmac, Mac};
+use interpolation::Resolver;
+pub use secrets::{
+    AgeFileVault, AwsSecretVault, AwsSsmVault, ExecVault, HashicorpVault, PasswordStoreVault,
+    SecureStoreVault, SopsVault,
+};
+use secrets::{CacheSettings, LazyVault, VaultCache, VaultError, VaultKind, VaultTrait};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+/// Key of the hashes shown for masked values, next to the config file
+const HASH_KEY_FILE: &str = "display.key";
+
+/// Key of the cached vaults, next to the config file
+const CACHE_KEY_FILE: &str = "cache.key";
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// Secrets read one by one, by vault name, see [`Config::fetch_secrets`]
+type Fetched = HashMap<String, (KeyRef, Option<String>)>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct KeyRef {
     path: PathBuf,
     key: String,
@@ -34,27 +55,49 @@ impl Display for KeyRef {
 pub struct Config {
     path: PathBuf,
     config: Configuration<ConfigValue>,
-    vaults: HashMap<String, Box<dyn VaultTrait>>,
+    vaults: HashMap<String, LazyVault>,
     default_vault: Option<String>,
     context: PathBuf,
     updated: Vec<String>,
+    cache: Option<CacheSettings>,
+    /// Directories mapped to a project id, see `commands::resolve_project`
+    projects: BTreeMap<PathBuf, String>,
+    /// Config file shared by the current project, layered under this one
+    project_file: Option<ProjectLayer>,
+    /// Environments declared for each project id
+    environments: BTreeMap<String, ProjectEnvironments>,
+    /// Environment passed with `--env`, used instead of the current one of the project
+    env_override: Option<String>,
+    protected: Protected,
+    /// Context passed with `--context` or SECRETS_MANAGER_CONTEXT, it is never saved
+    context_override: Option<PathBuf>,
+    /// Writes and reveals of protected contexts and vaults were confirmed with `--yes-i-mean-prod`
+    confirmed: bool,
+    /// Serve vaults from the cache only, without reaching their backends
+    offline: bool,
+    /// Print warnings that are only worth reading when looking into an issue
+    verbose: bool,
+    /// Advisory lock on the config file, held until the config is saved or dropped
+    _lock: File,
 }
 
 impl Config {
     pub async fn load(path: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
-        let res = if !path.exists() {
+        let lock = Self::lock(&path).await?;
+        let mut res = if !path.exists() {
             ConfigFileData::default()
         } else {
-            let res = serde_json::from_reader(std::fs::File::open(&path)?).map_err(|e| {
+            let res: ConfigFileData = serde_json::from_reader(File::open(&path)?).map_err(|e| {
                 format!("Failed to parse config file.\nif you used a previous version of secrets-manager, run `secrets-manager config migrate`\n {}", e)
             })?;
             res
         };
-        let len = res.secrets.len();
-        let mut vaults = HashMap::with_capacity(len);
-        for (name, kind) in res.secrets.into_iter() {
-            vaults.insert(name, kind.into_vault().await?);
-        }
+        res.upgrade();
+        let vaults = res
+            .secrets
+            .into_iter()
+            .map(|(name, kind)| (name, LazyVault::new(kind)))
+            .collect();
         Ok(Self {
             path,
             default_vault: res.default_secret,
@@ -62,55 +105,322 @@ impl Config {
             vaults,
             context: res.context,
             updated: Vec::new(),
+            cache: res.cache,
+            projects: res.projects,
+            project_file: None,
+            environments: res.environments,
+            env_override: None,
+            protected: res.protected,
+            context_override: None,
+            confirmed: false,
+            offline: false,
+            verbose: false,
+            _lock: lock,
         })
     }
 
-    pub fn get(&self, key_ref: &KeyRef) -> Option<&str> {
-        let value = self.config.get(&key_ref);
-        let value = if let Some(value) = value {
-            match value {
-                ConfigValue::Secret(name, key_ref) => self.resolve_secret(name, key_ref),
-                ConfigValue::Value(value) => Some(value.as_str()),
+    /// Takes an exclusive lock on `<path>.lock`, waiting for other invocations to release it
+    pub(crate) async fn lock(path: &Path) -> Result<File, std::io::Error> {
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(lock_path)?;
+        // waiting for the lock blocks, keep it off the runtime threads
+        tokio::task::spawn_blocking(move || file.lock().map(|_| file))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+
+    /// Resolves `key_ref` in its context, expanding `${KEY}` and `${vault::path.key}` references.
+    /// Secrets missing from their vault are an error
+    pub async fn get(&self, key_ref: &KeyRef) -> Result<Option<String>, ConfigError> {
+        self.resolve_key(key_ref, true, true).await
+    }
+
+    /// Like [`Config::get`], but values containing vault secrets are masked unless `reveal` is set
+    pub async fn get_for_display(
+        &self,
+        key_ref: &KeyRef,
+        reveal: bool,
+    ) -> Result<Option<String>, ConfigError> {
+        self.resolve_key(key_ref, reveal, false).await
+    }
+
+    /// Resolves all the keys visible from `key`, values are never masked
+    /// and secrets missing from their vault are an error
+    pub async fn get_all(&self, key: &Path) -> Result<BTreeMap<&str, String>, ConfigError> {
+        self.resolve_all(key, true, true).await
+    }
+
+    /// Like [`Config::get_all`], but values containing vault secrets are masked unless `reveal` is set
+    pub async fn get_all_for_display(
+        &self,
+        key: &Path,
+        reveal: bool,
+    ) -> Result<BTreeMap<&str, String>, ConfigError> {
+        self.resolve_all(key, reveal, false).await
+    }
+
+    async fn resolve_key(
+        &self,
+        key_ref: &KeyRef,
+        reveal: bool,
+        strict: bool,
+    ) -> Result<Option<String>, ConfigError> {
+        let secrets = self
+            .resolver(&key_ref.path, None)
+            .required_secrets([key_ref.key.as_str()])?;
+        let fetched = self.fetch_secrets(&secrets).await?;
+        self.load_required(&secrets, &fetched).await?;
+        let mut resolver = self.resolver(&key_ref.path, Some(&fetched)).strict(strict);
+        let hash_key = if reveal { Vec::new() } else { self.hash_key()? };
+        Self::render(&mut resolver, &key_ref.key, reveal, &hash_key)
+    }
+
+    async fn resolve_all(
+        &self,
+        key: &Path,
+        reveal: bool,
+        strict: bool,
+    ) -> Result<BTreeMap<&str, String>, ConfigError> {
+        let resolver = self.resolver(key, None);
+        let keys: Vec<_> = resolver.keys().collect();
+        let secrets = resolver.required_secrets(keys.iter().copied())?;
+        let fetched = self.fetch_secrets(&secrets).await?;
+        self.load_required(&secrets, &fetched).await?;
+        let mut resolver = self.resolver(key, Some(&fetched)).strict(strict);
+        let hash_key = if reveal { Vec::new() } else { self.hash_key()? };
+        let mut res = BTreeMap::new();
+        for key in keys {
+            if let Some(value) = Self::render(&mut resolver, key, reveal, &hash_key)? {
+                res.insert(key, value);
             }
-        } else {
-            return None;
+        }
+        Ok(res)
+    }
+
+    /// Names of the existing vaults read to resolve `key` in `path`, or all the keys visible
+    /// from `path` if `key` is `None`, following references. Nothing is loaded
+    pub fn required_vaults(
+        &self,
+        path: &Path,
+        key: Option<&str>,
+    ) -> Result<BTreeSet<String>, ConfigError> {
+        let resolver = self.resolver(path, None);
+        let vaults = match key {
+            Some(key) => resolver.required_vaults([key])?,
+            None => resolver.required_vaults(resolver.keys())?,
+        };
+        Ok(vaults
+            .into_iter()
+            .filter(|name| self.vault_exists(name))
+            .map(String::from)
+            .collect())
+    }
+
+    /// Masked values are shown as their definition followed by a short hash of the resolved value,
+    /// so they can be compared without being revealed. The hash is keyed with `hash_key`, so that
+    /// low entropy secrets can't be guessed from it without access to this machine
+    fn render<'a>(
+        resolver: &mut Resolver<'a, impl Fn(&str, &KeyRef) -> Option<&'a str>>,
+        key: &str,
+        reveal: bool,
+        hash_key: &[u8],
+    ) -> Result<Option<String>, ConfigError> {
+        let Some(value) = resolver.resolve(key)? else {
+            return Ok(None);
         };
-        value
+        if reveal || !resolver.is_sensitive(key) {
+            return Ok(Some(value));
+        }
+        let definition = resolver
+            .definition(key)
+            .map(|v| v.to_string())
+            .unwrap_or_default();
+        let mut mac = Hmac::<Sha256>::new_from_slice(hash_key).expect("hmac accepts any key size");
+        mac.update(value.as_bytes());
+        let hash = mac.finalize().into_bytes();
+        let short: String = hash[..4].iter().map(|b| format!("{:02x}", b)).collect();
+        Ok(Some(format!("{} (hash:{})", definition, short)))
     }
 
+    /// Key of the hashes of masked values, generated the first time it is needed
+    fn hash_key(&self) -> Result<Vec<u8>, std::io::Error> {
+        let path = self.path.with_file_name(HASH_KEY_FILE);
+        match std::fs::read(&path) {
+            Ok(key) if !key.is_empty() => return Ok(key),
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let mut key = vec![0; 32];
+        OsRng.fill_bytes(&mut key);
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(&path)?.write_all(&key)?;
+        Ok(key)
+    }
+
+    /// Only looks into vaults that are already loaded, see [`Config::load_vaults`]
     fn resolve_secret(&self, name: &str, key_ref: &KeyRef) -> Option<&str> {
-        let Some(vault) = self.vaults.get(name) else {
-            return None;
-        };
-        vault.get().get(&key_ref).map(|v| v.as_str())
+        let vault = self.vaults.get(name)?.get()?;
+        vault.get().get(key_ref).map(|v| v.as_str())
     }
 
-    pub fn get_all(&self, key: &Path) -> HashMap<&str, String> {
-        self.config
-            .get_all(key)
+    /// Resolver of the values visible from `path`, secrets are read from `fetched`
+    /// if they are in it, otherwise from their loaded vault
+    fn resolver<'a>(
+        &'a self,
+        path: &Path,
+        fetched: Option<&'a Fetched>,
+    ) -> Resolver<'a, impl Fn(&str, &KeyRef) -> Option<&'a str>> {
+        let values = self
+            .layered_values(path)
             .into_iter()
-            .map(|(key, v)| {
-                let value: String = match v {
-                    ConfigValue::Secret(ref name, ref key_ref) => self
-                        .resolve_secret(name, key_ref)
-                        .map(|v| v.to_string())
-                        .unwrap_or(v.to_string()),
-                    ConfigValue::Value(v) => v.to_owned(),
-                };
-                (key.as_str(), value)
-            })
-            .collect()
+            .map(|(key, (value, _))| (key, value))
+            .collect();
+        Resolver::new(values, move |name: &str, key_ref: &KeyRef| {
+            match fetched
+                .and_then(|f| f.get(name))
+                .filter(|(k, _)| k == key_ref)
+            {
+                Some((_, value)) => value.as_deref(),
+                None => self.resolve_secret(name, key_ref),
+            }
+        })
+    }
+
+    /// Reads the secrets that are the only ones needed from their vault one by one, when
+    /// their vault is not loaded and can read single keys, see [`VaultKind::can_fetch`].
+    /// The vaults of the other secrets have to be loaded
+    async fn fetch_secrets(&self, secrets: &[(&str, KeyRef)]) -> Result<Fetched, ConfigError> {
+        let mut res = Fetched::new();
+        // the cache holds whole vaults, it is used or refreshed instead
+        if self.offline || self.cache.is_some() {
+            return Ok(res);
+        }
+        for (name, key_ref) in secrets {
+            let Some(vault) = self.vaults.get(*name) else {
+                continue;
+            };
+            let single = secrets.iter().filter(|(n, _)| n == name).count() == 1;
+            if single && !vault.is_loaded() && vault.kind().can_fetch() {
+                let value = vault.kind().fetch(key_ref).await?;
+                res.insert(name.to_string(), (key_ref.clone(), value));
+            }
+        }
+        Ok(res)
     }
 
-    pub fn set(
+    /// Loads the vaults of `secrets` that were not fetched
+    async fn load_required(
+        &self,
+        secrets: &[(&str, KeyRef)],
+        fetched: &Fetched,
+    ) -> Result<(), ConfigError> {
+        let vaults = secrets
+            .iter()
+            .map(|(name, _)| *name)
+            .filter(|name| self.vault_exists(name) && !fetched.contains_key(*name));
+        self.load_vaults(vaults).await
+    }
+
+    /// Values visible from `path` with the layer they come from. Values of deeper paths win,
+    /// at the same path the global config overrides the project file
+    fn layered_values(&self, path: &Path) -> BTreeMap<&String, (&ConfigValue, Layer)> {
+        let mut res = BTreeMap::new();
+        let ancestors: Vec<_> = path.ancestors().collect();
+        for path in ancestors.into_iter().rev() {
+            let project = self
+                .project_file
+                .as_ref()
+                .and_then(|p| p.config.section(path));
+            let layers = [
+                (project, Layer::Project),
+                (self.config.section(path), Layer::Global),
+            ];
+            for (values, layer) in layers {
+                for (key, value) in values.into_iter().flatten() {
+                    res.insert(key, (value, layer));
+                }
+            }
+        }
+        res
+    }
+
+    /// Layer the value of `key_ref` is read from
+    pub fn layer_of(&self, key_ref: &KeyRef) -> Option<Layer> {
+        self.layered_values(&key_ref.path)
+            .get(&key_ref.key)
+            .map(|(_, layer)| *layer)
+    }
+
+    /// Layers the project file of the current project under the global config.
+    /// Its keys are relative to the project and are stored under `/<project_id>`
+    pub fn load_project_file(
+        &mut self,
+        file: PathBuf,
+        project_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let root = PathBuf::from("/").join(project_id);
+        let data = match std::fs::read_to_string(&file) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| format!("Failed to parse {}: {}", file.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ProjectFileData::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut config = Configuration::new();
+        for key_ref in data.config.keys("/") {
+            if let Some(value) = data.config.get_exact(&key_ref) {
+                let path = root.join(key_ref.path.strip_prefix("/").unwrap_or(&key_ref.path));
+                config.set(
+                    KeyRef {
+                        path,
+                        key: key_ref.key.clone(),
+                    },
+                    value.clone(),
+                );
+            }
+        }
+        self.project_file = Some(ProjectLayer {
+            file,
+            root,
+            config,
+            updated: false,
+        });
+        Ok(())
+    }
+
+    /// Path of the project file, if one exists
+    pub fn project_file(&self) -> Option<&Path> {
+        self.project_file
+            .as_ref()
+            .map(|p| p.file.as_path())
+            .filter(|file| file.exists())
+    }
+
+    pub async fn set(
         &mut self,
         key_ref: KeyRef,
         value: ConfigValue,
+    ) -> Result<Option<ConfigValue>, ConfigError> {
+        self.set_in(Layer::Global, key_ref, value).await
+    }
+
+    pub async fn set_in(
+        &mut self,
+        layer: Layer,
+        key_ref: KeyRef,
+        value: ConfigValue,
     ) -> Result<Option<ConfigValue>, ConfigError> {
         let value = match value {
             ConfigValue::Value(_) => value,
             ConfigValue::Secret(ref name, ref secret_ref)
-                if self.get_secret(name, secret_ref)?.is_none() =>
+                if self.get_secret(name, secret_ref).await?.is_none() =>
             {
                 return Err(ConfigError::SecretNotFound(
                     name.to_string(),
@@ -119,7 +429,14 @@ impl Config {
             }
             v => v,
         };
-        let res = self.config.set(key_ref, value);
+        let res = match layer {
+            Layer::Global => self.config.set(key_ref, value),
+            Layer::Project => {
+                let project = self.project_layer(&key_ref)?;
+                project.updated = true;
+                project.config.set(key_ref, value)
+            }
+        };
         Ok(res)
     }
 
@@ -127,135 +444,808 @@ impl Config {
         self.config.remove(key_ref)
     }
 
+    pub fn remove_from(
+        &mut self,
+        layer: Layer,
+        key_ref: &KeyRef,
+    ) -> Result<Option<ConfigValue>, ConfigError> {
+        match layer {
+            Layer::Global => Ok(self.remove(key_ref)),
+            Layer::Project => {
+                let project = self.project_layer(key_ref)?;
+                let removed = project.config.remove(key_ref);
+                project.updated |= removed.is_some();
+                Ok(removed)
+            }
+        }
+    }
+
+    /// Project file that `key_ref` can be written to
+    fn project_layer(&mut self, key_ref: &KeyRef) -> Result<&mut ProjectLayer, ConfigError> {
+        match self.project_file {
+            Some(ref mut project) if key_ref.path.starts_with(&project.root) => Ok(project),
+            _ => Err(ConfigError::OutsideProject(key_ref.to_string())),
+        }
+    }
+
     pub async fn save(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let cache = self.cache.map(|_| self.vault_cache());
         for name in self.updated {
-            self.vaults
-                .get_mut(&name)
-                .expect("Vault exists if it was updated")
-                .save()
-                .await?;
+            // vaults are only marked as updated after being loaded
+            if let Some(vault) = self.vaults.get_mut(&name).and_then(|v| v.get_mut()) {
+                vault.save().await?;
+                if let Some(ref cache) = cache {
+                    // the cache is best effort, a stale entry expires with its ttl
+                    let _ = cache.write(&name, &vault.to_vault_kind(), vault.as_ref());
+                }
+            }
+        }
+        if let Some(project) = self.project_file.filter(|p| p.updated) {
+            project.write()?;
         }
-        let mut secrets = HashMap::new();
+        let mut secrets = BTreeMap::new();
         for (name, v) in self.vaults {
-            let kind = v.into_vault_kind();
+            let kind = v.to_vault_kind();
             secrets.insert(name, kind);
         }
         let data = ConfigFileData {
+            version: CONFIG_VERSION,
             context: self.context,
             config: self.config,
             secrets,
             default_secret: self.default_vault,
+            cache: self.cache,
+            projects: self.projects,
+            environments: self.environments,
+            protected: self.protected,
         };
-        let file = std::fs::File::create(&self.path)?;
-        serde_json::to_writer_pretty(file, &data)?;
+        data.write(&self.path)?;
         Ok(())
     }
 
     pub fn get_vault_name(&self, name: Option<&str>) -> Result<String, ConfigError> {
-        let name = self
-            .default_vault
-            .as_deref()
-            .or(name)
+        let name = name
+            .or(self.default_vault.as_deref())
             .ok_or(ConfigError::VaultNotSpecified)?;
         Ok(name.to_string())
     }
+
+    /// Environments declared for `project`, by name
+    pub fn environments(&self, project: &str) -> Option<&BTreeMap<String, Environment>> {
+        self.environments.get(project).map(|p| &p.declared)
+    }
+
+    pub fn create_env(
+        &mut self,
+        project: &str,
+        name: String,
+        env: Environment,
+    ) -> Result<(), ConfigError> {
+        let envs = self.environments.entry(project.to_string()).or_default();
+        if envs.declared.contains_key(&name) {
+            return Err(ConfigError::EnvAlreadyExists(name));
+        }
+        envs.declared.insert(name, env);
+        Ok(())
+    }
+
+    /// Makes `name` the current environment of `project`
+    pub fn use_env(&mut self, project: &str, name: &str) -> Result<(), ConfigError> {
+        let envs = self
+            .environments
+            .get_mut(project)
+            .filter(|p| p.declared.contains_key(name))
+            .ok_or_else(|| ConfigError::EnvNotFound(name.to_string(), project.to_string()))?;
+        envs.current = Some(name.to_string());
+        Ok(())
+    }
+
+    /// Stops using the current environment of `project`, returns its name if there was one
+    pub fn unuse_env(&mut self, project: &str) -> Option<String> {
+        self.environments
+            .get_mut(project)
+            .and_then(|p| p.current.take())
+    }
+
+    pub fn set_confirmed(&mut self, confirmed: bool) {
+        self.confirmed = confirmed;
+    }
+
+    pub fn is_confirmed(&self) -> bool {
+        self.confirmed
+    }
+
+    /// Marks the context at `path`, and the ones under it, as protected or not
+    pub fn protect_context(&mut self, path: PathBuf, protected: bool) -> bool {
+        if protected {
+            self.protected.contexts.insert(path)
+        } else {
+            self.protected.contexts.remove(&path)
+        }
+    }
+
+    pub fn protect_vault(&mut self, name: &str, protected: bool) -> Result<bool, ConfigError> {
+        if !self.vault_exists(name) {
+            return Err(ConfigError::VaultNotFound(name.to_string()));
+        }
+        Ok(if protected {
+            self.protected.vaults.insert(name.to_string())
+        } else {
+            self.protected.vaults.remove(name)
+        })
+    }
+
+    /// Whether `path` is under a protected context or the context of a protected environment
+    pub fn is_protected_path(&self, path: &Path) -> bool {
+        let mut env_contexts = self.environments.iter().flat_map(|(project, envs)| {
+            envs.declared
+                .values()
+                .filter(|env| env.protected)
+                .map(move |env| {
+                    let context = env.context.strip_prefix("/").unwrap_or(&env.context);
+                    PathBuf::from("/").join(project).join(context)
+                })
+        });
+        self.protected.contexts.iter().any(|p| path.starts_with(p))
+            || env_contexts.any(|p| path.starts_with(p))
+    }
+
+    /// Whether any context or vault is protected, directly or through a protected environment
+    pub fn has_protected(&self) -> bool {
+        !self.protected.is_empty()
+            || self
+                .environments
+                .values()
+                .flat_map(|envs| envs.declared.values())
+                .any(|env| env.protected)
+    }
+
+    /// Whether vault `name` is protected or is the vault of a protected environment
+    pub fn is_protected_vault(&self, name: &str) -> bool {
+        self.protected.vaults.contains(name)
+            || self
+                .environments
+                .values()
+                .flat_map(|envs| envs.declared.values())
+                .any(|env| env.protected && env.vault.as_deref() == Some(name))
+    }
+
+    pub fn set_env_override(&mut self, name: Option<String>) {
+        self.env_override = name;
+    }
+
+    /// Environment of `project` in use: the one passed with `--env`, otherwise its current one.
+    /// The flag is true when it comes from `--env`
+    pub fn active_env(
+        &self,
+        project: &str,
+    ) -> Result<Option<(&str, &Environment, bool)>, ConfigError> {
+        let envs = self.environments.get(project);
+        let (name, overridden) = match (&self.env_override, envs) {
+            (Some(name), _) => (name, true),
+            (
+                None,
+                Some(ProjectEnvironments {
+                    current: Some(name),
+                    ..
+                }),
+            ) => (name, false),
+            (None, _) => return Ok(None),
+        };
+        let env = envs
+            .and_then(|p| p.declared.get(name))
+            .ok_or_else(|| ConfigError::EnvNotFound(name.to_string(), project.to_string()))?;
+        Ok(Some((name, env, overridden)))
+    }
     pub fn set_default_vault(&mut self, name: String) {
         self.default_vault = Some(name);
     }
     pub fn set_current_context(&mut self, name: PathBuf) {
         self.context = name;
     }
+    /// Context of this invocation, the override if there is one, otherwise the saved one
     pub fn get_current_context(&self) -> &Path {
-        &self.context
+        self.context_override.as_deref().unwrap_or(&self.context)
     }
 
-    pub fn set_secret(
+    /// Overrides the saved context for this invocation only
+    pub fn set_context_override(&mut self, context: Option<PathBuf>) {
+        self.context_override = context;
+    }
+
+    pub fn context_override(&self) -> Option<&Path> {
+        self.context_override.as_deref()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn projects(&self) -> &BTreeMap<PathBuf, String> {
+        &self.projects
+    }
+
+    /// Uses `id` as the project of `dir` and its subdirectories
+    pub fn map_project(&mut self, dir: PathBuf, id: String) {
+        self.projects.insert(dir, id);
+    }
+
+    pub fn unmap_project(&mut self, dir: &Path) -> Option<String> {
+        self.projects.remove(dir)
+    }
+
+    pub fn cache_settings(&self) -> Option<CacheSettings> {
+        self.cache
+    }
+
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    pub fn is_verbose(&self) -> bool {
+        self.verbose
+    }
+
+    /// Whether config values or environments are stored for project `id`
+    pub fn has_project_config(&self, id: &str) -> bool {
+        self.config.keys(Path::new("/").join(id)).next().is_some()
+            || self.environments.contains_key(id)
+    }
+
+    /// Enables the cache of vault contents, or disables it and deletes the cached copies
+    pub fn set_cache(&mut self, cache: Option<CacheSettings>) -> Result<(), ConfigError> {
+        if cache.is_none() {
+            self.vault_cache().clear().map_err(VaultError::from)?;
+        }
+        self.cache = cache;
+        Ok(())
+    }
+
+    /// Fetches the given vaults from their backends, bypassing the cache, and stores them in it.
+    /// Returns the version id fetched for each vault
+    pub async fn refresh(
+        &mut self,
+        names: &[String],
+    ) -> Result<Vec<(String, Option<String>)>, ConfigError> {
+        let cache = self.vault_cache();
+        let mut res = Vec::new();
+        for name in names {
+            if self.offline {
+                return Err(VaultError::Offline(name.to_string()).into());
+            }
+            let lazy = self
+                .vaults
+                .get_mut(name)
+                .ok_or_else(|| ConfigError::VaultNotFound(name.to_string()))?;
+            let vault = lazy.kind().clone().into_vault().await?;
+            cache
+                .write(name, lazy.kind(), vault.as_ref())
+                .map_err(VaultError::from)?;
+            res.push((name.to_string(), vault.version()));
+            lazy.replace(vault);
+        }
+        Ok(res)
+    }
+
+    /// Uses `vault`, written directly to its backend, as the loaded copy of vault `name`.
+    /// Its descriptor, e.g the version it saved, is stored with the config
+    pub fn replace_vault(
+        &mut self,
+        name: &str,
+        vault: Box<dyn VaultTrait>,
+    ) -> Result<(), ConfigError> {
+        if self.cache.is_some() {
+            // the cache is best effort, a stale entry expires with its ttl
+            let _ = self
+                .vault_cache()
+                .write(name, &vault.to_vault_kind(), vault.as_ref());
+        }
+        self.vaults
+            .get_mut(name)
+            .ok_or_else(|| ConfigError::VaultNotFound(name.to_string()))?
+            .replace(vault);
+        Ok(())
+    }
+
+    pub fn vault_names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.vaults.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// The key is kept next to the config file, out of the directory of the entries
+    fn vault_cache(&self) -> VaultCache {
+        let dir = match self.path.parent() {
+            Some(dir) => dir.to_path_buf(),
+            None => PathBuf::new(),
+        };
+        VaultCache::new(dir.join("cache"), dir.join(CACHE_KEY_FILE))
+    }
+
+    /// Cached copy of a vault, if it can be used: any copy while offline,
+    /// otherwise only copies younger than the ttl when the cache is enabled
+    fn read_cache(
+        &self,
+        name: &str,
+        kind: &VaultKind,
+    ) -> Result<Option<Box<dyn VaultTrait>>, VaultError> {
+        let max_age = match (self.offline, self.cache) {
+            (true, _) => None,
+            (false, Some(settings)) => Some(Duration::from_secs(settings.ttl)),
+            (false, None) => return Ok(None),
+        };
+        match self.vault_cache().read(name, kind, max_age) {
+            Ok(vault) => Ok(vault.map(|v| Box::new(v) as Box<dyn VaultTrait>)),
+            Err(e) if self.offline => Err(e.into()),
+            // an unreadable cache is fetched again
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub async fn set_secret(
         &mut self,
         name: &str,
         key: KeyRef,
         value: String,
     ) -> Result<Option<String>, ConfigError> {
-        let vault = self.get_vault_mut(name)?;
+        let vault = self.get_vault_mut(name).await?;
         let replaced = vault.get_mut().set(key, value);
         self.updated.push(name.to_string());
         Ok(replaced)
     }
 
-    pub fn remove_secret(
+    pub async fn remove_secret(
         &mut self,
         name: &str,
         key: &KeyRef,
     ) -> Result<Option<String>, ConfigError> {
-        let vault = self.get_vault_mut(name)?;
+        let vault = self.get_vault_mut(name).await?;
         let removed = vault.get_mut().remove(key);
         self.updated.push(name.to_string());
         Ok(removed)
     }
-    pub fn get_secret(&self, name: &str, key_ref: &KeyRef) -> Result<Option<&str>, ConfigError> {
-        let vault = self
-            .vaults
-            .get(name)
-            .ok_or_else(|| ConfigError::VaultNotFound(name.to_string()))?;
-        let res = vault.get().get(key_ref);
-        Ok(res.map(|x| x.as_str()))
+    /// Reads a single secret, without loading its vault if it can read single keys
+    pub async fn get_secret(
+        &self,
+        name: &str,
+        key_ref: &KeyRef,
+    ) -> Result<Option<String>, ConfigError> {
+        let secret = [(name, key_ref.clone())];
+        if let Some((_, value)) = self.fetch_secrets(&secret).await?.remove(name) {
+            return Ok(value);
+        }
+        let vault = self.get_vault(name).await?;
+        Ok(vault.get().get(key_ref).cloned())
     }
 
-    pub fn get_all_secrets(
+    pub async fn get_all_secrets(
         &self,
         name: &str,
         path: &Path,
-    ) -> Result<HashMap<&String, &String>, ConfigError> {
-        let vault = self.get_vault(name)?;
+    ) -> Result<BTreeMap<&String, &String>, ConfigError> {
+        let vault = self.get_vault(name).await?;
         let res = vault.get().get_all(path);
         Ok(res)
     }
 
-    pub async fn add_vault(&mut self, name: String, vault: VaultKind) -> Result<(), ConfigError> {
+    /// Registers a vault that has already been created on its backend
+    pub fn add_vault(&mut self, name: String, vault: VaultKind) -> Result<(), ConfigError> {
         if self.vault_exists(&name) {
             return Err(ConfigError::VaultAlreadyExists);
         }
-        let vault = vault.into_vault().await?;
-        self.vaults.insert(name.clone(), vault);
-        self.updated.push(name);
+        self.vaults.insert(name, LazyVault::new(vault));
         Ok(())
     }
-    pub fn display(&self) -> String {
-        self.config.display()
+    /// Tree of all keys, each value resolved in the context it is defined in.
+    /// Unless `reveal` is set, values that need a vault are shown as defined and no vault is loaded
+    pub async fn display(&self, reveal: bool) -> Result<String, ConfigError> {
+        let mut paths: BTreeMap<PathBuf, Vec<String>> = BTreeMap::new();
+        let project = self.project_file.as_ref().map(|p| &p.config);
+        for key_ref in self
+            .config
+            .keys("/")
+            .chain(project.into_iter().flat_map(|c| c.keys("/")))
+        {
+            let keys = paths.entry(key_ref.path).or_default();
+            if !keys.contains(&key_ref.key) {
+                keys.push(key_ref.key);
+            }
+        }
+        // vaults that failed to load, their values are shown with the error
+        let mut failed: HashMap<&str, String> = HashMap::new();
+        let mut res = Configuration::<String>::new();
+        for (path, keys) in paths {
+            let mut resolver = self.resolver(&path, None);
+            for key in keys {
+                let definition = resolver
+                    .definition(&key)
+                    .map(|v| v.to_string())
+                    .unwrap_or_default();
+                let value = match resolver.required_vaults([key.as_str()]) {
+                    // masked values are shown as they are defined, without loading their vaults
+                    Ok(vaults) if !reveal && !vaults.is_empty() => Ok(definition.clone()),
+                    Ok(vaults) => {
+                        for name in vaults.iter().filter(|name| self.vault_exists(name)) {
+                            if !failed.contains_key(name) {
+                                if let Err(e) = self.load_vaults([*name]).await {
+                                    failed.insert(name, e.to_string());
+                                }
+                            }
+                        }
+                        match vaults.iter().find_map(|name| failed.get(name)) {
+                            Some(e) => Err(e.clone()),
+                            None => resolver
+                                .resolve(&key)
+                                .map(Option::unwrap_or_default)
+                                .map_err(|e| e.to_string()),
+                        }
+                    }
+                    Err(e) => Err(e.to_string()),
+                };
+                // one broken value or vault does not hide the others
+                let value = value.unwrap_or_else(|e| format!("{} (error: {})", definition, e));
+                res.set(
+                    KeyRef {
+                        path: path.clone(),
+                        key,
+                    },
+                    value,
+                );
+            }
+        }
+        Ok(res.display())
     }
 
-    pub fn display_vault(&self, name: &str) -> Result<String, ConfigError> {
-        let vault = self.get_vault(name)?;
+    /// Copies the secrets stored under `path` in vault `from` to the same keys in vault `to`.
+    /// Keys that already exist in `to` with another value are handled according to `policy`,
+    /// with [`ConflictPolicy::Fail`] nothing is copied if any key conflicts
+    pub async fn copy_secrets(
+        &mut self,
+        from: &str,
+        to: &str,
+        path: &Path,
+        policy: ConflictPolicy,
+    ) -> Result<CopyReport, ConfigError> {
+        let source: Vec<(KeyRef, String)> = {
+            let vault = self.get_vault(from).await?;
+            vault
+                .get()
+                .keys(path)
+                .filter_map(|k| Some((k.clone(), vault.get().get(&k)?.clone())))
+                .collect()
+        };
+        let vault = self.get_vault_mut(to).await?;
+        let mut report = CopyReport::default();
+        let mut values = Vec::new();
+        for (key_ref, value) in source {
+            match vault.get().get_exact(&key_ref) {
+                Some(existing) if *existing == value => report.unchanged.push(key_ref),
+                Some(_) if policy != ConflictPolicy::Overwrite => report.conflicts.push(key_ref),
+                _ => values.push((key_ref, value)),
+            }
+        }
+        if policy == ConflictPolicy::Fail && !report.conflicts.is_empty() {
+            return Err(ConfigError::ExistingKeys(
+                to.to_string(),
+                report.conflicts.iter().map(|k| k.to_string()).collect(),
+            ));
+        }
+        for (key_ref, value) in values {
+            vault.get_mut().set(key_ref.clone(), value);
+            report.copied.push(key_ref);
+        }
+        if !report.copied.is_empty() {
+            self.updated.push(to.to_string());
+        }
+        Ok(report)
+    }
+
+    /// Points the `ConfigValue::Secret` values referencing keys of vault `from` that hold
+    /// the same value in vault `to` after `report`, copied or unchanged, to vault `to`.
+    /// Keys skipped as conflicts keep their reference. Returns the config keys that were changed
+    pub fn rewrite_secret_refs(
+        &mut self,
+        from: &str,
+        to: &str,
+        report: &CopyReport,
+    ) -> Vec<KeyRef> {
+        let mut res = Vec::new();
+        for key_ref in self.config.keys("/").collect::<Vec<_>>() {
+            if let Some(ConfigValue::Secret(name, secret_ref)) = self.config.get_exact(&key_ref) {
+                let same_value =
+                    report.copied.contains(secret_ref) || report.unchanged.contains(secret_ref);
+                if name == from && same_value {
+                    let value = ConfigValue::Secret(to.to_string(), secret_ref.clone());
+                    self.config.set(key_ref.clone(), value);
+                    res.push(key_ref);
+                }
+            }
+        }
+        res
+    }
+
+    pub async fn display_vault(&self, name: &str) -> Result<String, ConfigError> {
+        let vault = self.get_vault(name).await?;
         Ok(vault.get().display())
     }
 
+    /// Descriptor of the vault, without loading it
+    pub fn vault_kind(&self, name: &str) -> Result<&VaultKind, ConfigError> {
+        self.vaults
+            .get(name)
+            .map(|v| v.kind())
+            .ok_or_else(|| ConfigError::VaultNotFound(name.to_string()))
+    }
+
     pub fn vault_exists(&self, name: &str) -> bool {
         self.vaults.contains_key(name)
     }
-    fn get_vault(&self, name: &str) -> Result<&Box<dyn VaultTrait>, ConfigError> {
+
+    /// Fetches the given vaults from their backends, concurrently, if they are not loaded yet
+    pub async fn load_vaults<'a>(
+        &self,
+        names: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), ConfigError> {
+        let mut tasks = tokio::task::JoinSet::new();
+        let mut seen = HashSet::new();
+        for name in names {
+            if !seen.insert(name) {
+                continue;
+            }
+            let vault = self
+                .vaults
+                .get(name)
+                .ok_or_else(|| ConfigError::VaultNotFound(name.to_string()))?;
+            if vault.is_loaded() {
+                continue;
+            }
+            if let Some(cached) = self.read_cache(name, vault.kind())? {
+                vault.set_cached(cached);
+                continue;
+            }
+            if self.offline {
+                return Err(VaultError::NotCached(name.to_string()).into());
+            }
+            let kind = vault.kind().clone();
+            let name = name.to_string();
+            tasks.spawn(async move { (name, kind.into_vault().await) });
+        }
+        while let Some(res) = tasks.join_next().await {
+            let (name, vault) = res.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+            let vault = vault?;
+            if self.cache.is_some() {
+                // the cache is best effort, failing to write it does not fail the command
+                let kind = self.vaults[&name].kind();
+                let _ = self.vault_cache().write(&name, kind, vault.as_ref());
+            }
+            self.vaults[&name].set_loaded(vault);
+        }
+        Ok(())
+    }
+
+    async fn get_vault(&self, name: &str) -> Result<&dyn VaultTrait, ConfigError> {
+        self.load_vaults([name]).await?;
         let vault = self
             .vaults
             .get(name)
+            .and_then(|v| v.get())
             .ok_or_else(|| ConfigError::VaultNotFound(name.to_string()))?;
         Ok(vault)
     }
 
-    fn get_vault_mut(&mut self, name: &str) -> Result<&mut Box<dyn VaultTrait>, ConfigError> {
+    /// Vaults are always modified on top of their live content, never of a cached copy
+    async fn get_vault_mut(&mut self, name: &str) -> Result<&mut Box<dyn VaultTrait>, ConfigError> {
+        if self.offline {
+            return Err(VaultError::Offline(name.to_string()).into());
+        }
+        self.load_vaults([name]).await?;
+        if let Some(lazy) = self.vaults.get_mut(name).filter(|v| v.is_cached()) {
+            let vault = lazy.kind().clone().into_vault().await?;
+            lazy.replace(vault);
+        }
         let vault = self
             .vaults
             .get_mut(name)
+            .and_then(|v| v.get_mut())
             .ok_or_else(|| ConfigError::VaultNotFound(name.to_string()))?;
         Ok(vault)
     }
 }
 
+/// Named context of a project, e.g dev or prod
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Environment {
+    /// Context path, under the project, holding the config of the environment
+    pub context: PathBuf,
+    /// Vault used by `secret` commands when none is passed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vault: Option<String>,
+    #[serde(default)]
+    pub protected: bool,
+}
+
+/// Contexts and vaults that need a confirmation to be written to or revealed
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Protected {
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    contexts: BTreeSet<PathBuf>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    vaults: BTreeSet<String>,
+}
+
+impl Protected {
+    fn is_empty(&self) -> bool {
+        self.contexts.is_empty() && self.vaults.is_empty()
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ProjectEnvironments {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    current: Option<String>,
+    #[serde(default)]
+    declared: BTreeMap<String, Environment>,
+}
+
+/// Where a config value is defined
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layer {
+    /// `.secrets-manager.json` of the project, meant to be committed with it
+    Project,
+    /// Config file of the user, it overrides the project file for keys set at the same path
+    Global,
+}
+
+impl Display for Layer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Layer::Project => write!(f, "project"),
+            Layer::Global => write!(f, "global"),
+        }
+    }
+}
+
+struct ProjectLayer {
+    file: PathBuf,
+    /// `/<project_id>`, the keys of the file are stored under it
+    root: PathBuf,
+    config: Configuration<ConfigValue>,
+    updated: bool,
+}
+
+impl ProjectLayer {
+    fn write(self) -> Result<(), std::io::Error> {
+        let mut config = Configuration::new();
+        for key_ref in self.config.keys(&self.root) {
+            if let Some(value) = self.config.get_exact(&key_ref) {
+                let path = key_ref
+                    .path
+                    .strip_prefix(&self.root)
+                    .unwrap_or(&key_ref.path);
+                let key_ref = KeyRef {
+                    path: PathBuf::from("/").join(path),
+                    key: key_ref.key.clone(),
+                };
+                config.set(key_ref, value.clone());
+            }
+        }
+        let mut content = serde_json::to_vec_pretty(&ProjectFileData { config })?;
+        content.push(b'\n');
+        // like the config file, under the config lock held by the command
+        write_atomically(&self.file, &content)
+    }
+}
+
+/// Content of `.secrets-manager.json`, only plain values and references to vault secrets
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectFileData {
+    #[serde(default)]
+    config: Configuration<ConfigValue>,
+}
+
+/// Version of the config file format, written with the config
+const CONFIG_VERSION: u32 = 1;
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct ConfigFileData {
+    /// 0 for files written before it was added, when values were not interpolated yet
+    #[serde(default)]
+    version: u32,
     config: Configuration<ConfigValue>,
     #[serde(default)]
     context: PathBuf,
     default_secret: Option<String>,
-    secrets: HashMap<String, secrets::VaultKind>,
+    secrets: BTreeMap<String, secrets::VaultKind>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cache: Option<CacheSettings>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    projects: BTreeMap<PathBuf, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    environments: BTreeMap<String, ProjectEnvironments>,
+    #[serde(default, skip_serializing_if = "Protected::is_empty")]
+    protected: Protected,
+}
+
+impl ConfigFileData {
+    /// Converts a config written by an older version to the current format
+    fn upgrade(&mut self) {
+        if self.version < 1 {
+            // `$` had no special meaning before values were interpolated
+            for key_ref in self.config.keys("/").collect::<Vec<_>>() {
+                if let Some(ConfigValue::Value(value)) = self.config.get_exact(&key_ref) {
+                    let value = ConfigValue::from_literal(value);
+                    self.config.set(key_ref, value);
+                }
+            }
+        }
+        self.version = CONFIG_VERSION;
+    }
+
+    /// Writes the config to `path`, it is never left truncated
+    pub(crate) fn write(&self, path: &Path) -> Result<(), std::io::Error> {
+        write_atomically(path, &serde_json::to_vec_pretty(self)?)
+    }
+}
+
+/// Writes `content` to a temporary file next to `path` then renames it over `path`,
+/// readers see either the old or the new content
+pub(crate) fn write_atomically(path: &Path, content: &[u8]) -> Result<(), std::io::Error> {
+    replace_atomically(path, 0o666, |tmp_path| {
+        OpenOptions::new()
+            .write(true)
+            .open(tmp_path)?
+            .write_all(content)
+    })
+}
+
+/// Lets `write` fill a temporary file next to `path`, created beforehand with the
+/// permissions `mode` on unix, then renames it over `path`
+pub(crate) fn replace_atomically<E: From<std::io::Error>>(
+    path: &Path,
+    mode: u32,
+    write: impl FnOnce(&Path) -> Result<(), E>,
+) -> Result<(), E> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or_default());
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+    let tmp_path = dir.join(tmp_name);
+    let res: Result<(), E> = (|| {
+        // left behind by a crashed process with the same pid
+        let _ = std::fs::remove_file(&tmp_path);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+        #[cfg(not(unix))]
+        let _ = mode;
+        options.open(&tmp_path)?;
+        write(&tmp_path)?;
+        OpenOptions::new().write(true).open(&tmp_path)?.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    })();
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    res?;
+    // persist the rename itself
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    Ok(())
 }
 
 impl std::str::FromStr for KeyRef {
@@ -273,23 +1263,60 @@ impl std::str::FromStr for KeyRef {
     }
 }
 
+/// What to do with keys that already exist in the destination of a copy, with another value
+#[derive(clap::ValueEnum, Default, Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    /// Keep the value of the destination
+    #[default]
+    Skip,
+    /// Replace the value of the destination
+    Overwrite,
+    /// Copy nothing and report the conflicting keys
+    Fail,
+}
+
+#[derive(Debug, Default)]
+pub struct CopyReport {
+    pub copied: Vec<KeyRef>,
+    /// Keys that already had the same value in the destination
+    pub unchanged: Vec<KeyRef>,
+    /// Keys that were left as they were in the destination
+    pub conflicts: Vec<KeyRef>,
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum ConfigError {
     #[error("Vault {0} not found")]
     VaultNotFound(String),
-    #[error("Secret {0} not found for vault {1}")]
+    #[error("Secret {1} not found in vault {0}")]
     SecretNotFound(String, String),
     #[error("vault name not specified, either pass --vault or set a default vault with `secrets-manager vault use`")]
     VaultNotSpecified,
     #[error("Vault already exists!")]
     VaultAlreadyExists,
+    #[error("{0} is outside of the current project, it cannot be stored in its project file")]
+    OutsideProject(String),
+    #[error("Environment {0} already exists")]
+    EnvAlreadyExists(String),
+    #[error("Environment {0} is not declared for project {1}, see `secrets-manager env list`")]
+    EnvNotFound(String, String),
+    #[error("Cyclic reference between config values: {0}")]
+    InterpolationCycle(String),
+    #[error("Key {0} referenced by {1} not found")]
+    UnresolvedReference(String, String),
+    #[error("Invalid reference in the value of {0}, use `$$` for a literal `$`")]
+    InvalidReference(String),
+    #[error("Keys already exist in vault {0} with another value: {}", .1.join(", "))]
+    ExistingKeys(String, Vec<String>),
     #[error(transparent)]
     Encoding(#[from] serde_json::Error),
     #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
     VaultError(#[from] secrets::VaultError),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum ConfigValue {
     Secret(String, KeyRef),
@@ -301,6 +1328,11 @@ impl ConfigValue {
         ConfigValue::Value(v)
     }
 
+    /// Value that is kept as is, `$` are escaped so that they are not interpolated
+    pub fn from_literal(v: &str) -> Self {
+        ConfigValue::Value(v.replace('$', "$$"))
+    }
+
     pub fn from_secret(name: String, v: String) -> Result<Self, Box<dyn std::error::Error>> {
         Ok(ConfigValue::Secret(name, v.parse()?))
     }
@@ -322,3 +1354,477 @@ impl Display for ConfigValue {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("secrets-manager-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn key_ref(key: &str) -> KeyRef {
+        key.parse().unwrap()
+    }
+
+    /// Exec vault holding `KEY`, each load is logged in `<dir>/loads`. With `wait_for`,
+    /// the load only succeeds if vault `wait_for` is being loaded at the same time
+    fn counting_vault(dir: &Path, name: &str, wait_for: Option<&str>) -> VaultKind {
+        let dir = dir.display();
+        let wait = wait_for.map_or_else(String::new, |other| {
+            format!(
+                "i=0; while [ ! -e {dir}/{other}.started ] && [ $i -lt 100 ]; do sleep 0.05; i=$((i+1)); done; \
+                 [ -e {dir}/{other}.started ] || exit 1;"
+            )
+        });
+        let script = format!(
+            r#"touch {dir}/{name}.started; {wait} echo {name} >> {dir}/loads; echo '{{"version": 1, "values": {{"KEY": "{name}"}}}}'"#
+        );
+        VaultKind::Exec(ExecVault::info(
+            String::from("sh"),
+            vec![String::from("-c"), script],
+        ))
+    }
+
+    fn loads(dir: &Path) -> Vec<String> {
+        let mut loads: Vec<_> = std::fs::read_to_string(dir.join("loads"))
+            .unwrap_or_default()
+            .lines()
+            .map(String::from)
+            .collect();
+        loads.sort();
+        loads
+    }
+
+    #[tokio::test]
+    async fn values_of_old_configs_are_not_interpolated() {
+        let dir = temp_dir("upgrade");
+        let path = dir.join("config.json");
+        let old = r#"{"config": {"/": {"PASS": "pa$$word", "OPEN": "abc${x"}}, "default_secret": null, "secrets": {}}"#;
+        std::fs::write(&path, old).unwrap();
+        let mut config = Config::load(path.clone()).await.unwrap();
+        assert_eq!(
+            config.get(&key_ref("PASS")).await.unwrap().unwrap(),
+            "pa$$word"
+        );
+        assert_eq!(
+            config.get(&key_ref("OPEN")).await.unwrap().unwrap(),
+            "abc${x"
+        );
+        config
+            .set(key_ref("URL"), ConfigValue::from_value("${PASS}".into()))
+            .await
+            .unwrap();
+        config.save().await.unwrap();
+
+        // upgraded once, values written afterwards are interpolated
+        let config = Config::load(path).await.unwrap();
+        assert_eq!(
+            config.get(&key_ref("PASS")).await.unwrap().unwrap(),
+            "pa$$word"
+        );
+        assert_eq!(
+            config.get(&key_ref("URL")).await.unwrap().unwrap(),
+            "pa$$word"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn vaults_are_loaded_once_and_only_when_needed() {
+        let dir = temp_dir("lazy");
+        let mut config = Config::load(dir.join("config.json")).await.unwrap();
+        config
+            .add_vault(
+                String::from("counted"),
+                counting_vault(&dir, "counted", None),
+            )
+            .unwrap();
+        config.config.set(
+            key_ref("app.PLAIN"),
+            ConfigValue::from_value("value".into()),
+        );
+        config.config.set(
+            key_ref("other.SECRET"),
+            ConfigValue::Secret(String::from("counted"), key_ref("KEY")),
+        );
+
+        assert_eq!(
+            config.get(&key_ref("app.PLAIN")).await.unwrap().unwrap(),
+            "value"
+        );
+        assert_eq!(config.get_all(Path::new("/app")).await.unwrap().len(), 1);
+        assert!(loads(&dir).is_empty());
+
+        for _ in 0..2 {
+            let value = config.get(&key_ref("other.SECRET")).await.unwrap();
+            assert_eq!(value.unwrap(), "counted");
+        }
+        assert_eq!(loads(&dir), ["counted"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn get_all_only_loads_vaults_to_reveal_them() {
+        let dir = temp_dir("display");
+        let mut config = Config::load(dir.join("config.json")).await.unwrap();
+        let broken = VaultKind::Exec(ExecVault::info(String::from("false"), Vec::new()));
+        config.add_vault(String::from("broken"), broken).unwrap();
+        config
+            .add_vault(
+                String::from("counted"),
+                counting_vault(&dir, "counted", None),
+            )
+            .unwrap();
+        for (key, vault) in [("app.OK", "counted"), ("app.KO", "broken")] {
+            let value = ConfigValue::Secret(vault.to_string(), key_ref("KEY"));
+            config.config.set(key_ref(key), value);
+        }
+        config
+            .config
+            .set(key_ref("app.URL"), ConfigValue::from_value("${OK}".into()));
+
+        let masked = config.display(false).await.unwrap();
+        assert!(masked.contains("OK: secret [counted::/KEY]"));
+        assert!(masked.contains("URL: ${OK}"));
+        assert!(loads(&dir).is_empty());
+
+        let revealed = config.display(true).await.unwrap();
+        assert!(revealed.contains("OK: counted"));
+        assert!(revealed.contains("URL: counted"));
+        assert!(revealed.contains("KO: secret [broken::/KEY] (error: "));
+
+        // the hash of masked values is keyed, and the key is kept
+        let value = config.get_for_display(&key_ref("app.OK"), false).await;
+        let value = value.unwrap().unwrap();
+        assert!(value.starts_with("secret [counted::/KEY] (hash:"));
+        let again = config.get_for_display(&key_ref("app.OK"), false).await;
+        assert_eq!(again.unwrap().unwrap(), value);
+        assert_eq!(std::fs::read(dir.join(HASH_KEY_FILE)).unwrap().len(), 32);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// SecureStore vault `name` in `dir` holding `values`
+    async fn secure_store(dir: &Path, name: &str, values: &[(&str, &str)]) -> VaultKind {
+        let path = dir.join(format!("{name}.json"));
+        let mut vault =
+            SecureStoreVault::create(path, Some(dir.join(format!("{name}.key")))).unwrap();
+        for (key, value) in values {
+            vault.get_mut().set(key_ref(key), *value);
+        }
+        vault.save().await.unwrap();
+        vault.to_vault_kind()
+    }
+
+    #[tokio::test]
+    async fn copy_secrets_handles_conflicts_and_rewrites_refs() {
+        let dir = temp_dir("copy");
+        let path = dir.join("config.json");
+        let mut config = Config::load(path.clone()).await.unwrap();
+        let from = [
+            ("app.NEW", "new"),
+            ("app.SAME", "same"),
+            ("app.OTHER", "mine"),
+            ("ROOT", "root"),
+        ];
+        let to = [("app.SAME", "same"), ("app.OTHER", "theirs")];
+        config
+            .add_vault(
+                String::from("from"),
+                secure_store(&dir, "from", &from).await,
+            )
+            .unwrap();
+        config
+            .add_vault(String::from("to"), secure_store(&dir, "to", &to).await)
+            .unwrap();
+        for key in ["app.NEW", "app.SAME", "app.OTHER", "ROOT"] {
+            let value = ConfigValue::Secret(String::from("from"), key_ref(key));
+            config.config.set(key_ref(&format!("dev.{key}")), value);
+        }
+
+        let err = config
+            .copy_secrets("from", "to", Path::new("/app"), ConflictPolicy::Fail)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::ExistingKeys(_, keys) if keys == ["app.OTHER"]));
+        let report = config
+            .copy_secrets("from", "to", Path::new("/app"), ConflictPolicy::Skip)
+            .await
+            .unwrap();
+        assert_eq!(report.copied, [key_ref("app.NEW")]);
+        assert_eq!(report.unchanged, [key_ref("app.SAME")]);
+        assert_eq!(report.conflicts, [key_ref("app.OTHER")]);
+
+        // only references to keys holding the same value in both vaults move
+        let mut rewritten = config.rewrite_secret_refs("from", "to", &report);
+        rewritten.sort_by_key(|k| k.to_string());
+        assert_eq!(rewritten, [key_ref("dev.app.NEW"), key_ref("dev.app.SAME")]);
+        let vault_of = |config: &Config, key: &str| match config.config.get_exact(&key_ref(key)) {
+            Some(ConfigValue::Secret(name, _)) => name.clone(),
+            _ => panic!("{key} is not a secret"),
+        };
+        assert_eq!(vault_of(&config, "dev.app.OTHER"), "from");
+        assert_eq!(vault_of(&config, "dev.ROOT"), "from");
+        config.save().await.unwrap();
+
+        let config = Config::load(path).await.unwrap();
+        assert_eq!(vault_of(&config, "dev.app.NEW"), "to");
+        assert_eq!(
+            config.get(&key_ref("dev.app.NEW")).await.unwrap().unwrap(),
+            "new"
+        );
+        assert_eq!(
+            config
+                .get(&key_ref("dev.app.OTHER"))
+                .await
+                .unwrap()
+                .unwrap(),
+            "mine"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn project_file_is_layered_under_the_global_config() {
+        let dir = temp_dir("layers");
+        let file = dir.join(commands::PROJECT_FILE);
+        let content =
+            r#"{"config": {"/": {"PORT": "80", "HOST": "project"}, "/dev": {"PORT": "8080"}}}"#;
+        std::fs::write(&file, content).unwrap();
+        let mut config = Config::load(dir.join("config.json")).await.unwrap();
+        config.load_project_file(file.clone(), "api").unwrap();
+        let value = |v: &str| ConfigValue::from_value(v.to_string());
+        config.config.set(key_ref("PORT"), value("1"));
+        config.config.set(key_ref("api.HOST"), value("global"));
+        config.config.set(key_ref("api.dev.USER"), value("global"));
+
+        // deeper paths win, the global config wins at the same path
+        let values = config.layered_values(Path::new("/api/dev"));
+        let layers: Vec<_> = values
+            .iter()
+            .map(|(key, (value, layer))| (key.as_str(), value.to_string(), *layer))
+            .collect();
+        assert_eq!(
+            layers,
+            [
+                ("HOST", String::from("global"), Layer::Global),
+                ("PORT", String::from("8080"), Layer::Project),
+                ("USER", String::from("global"), Layer::Global),
+            ]
+        );
+        assert_eq!(
+            config.layer_of(&key_ref("api.dev.PORT")),
+            Some(Layer::Project)
+        );
+        assert_eq!(config.layer_of(&key_ref("api.PORT")), Some(Layer::Project));
+        assert_eq!(config.layer_of(&key_ref("PORT")), Some(Layer::Global));
+        assert_eq!(config.layer_of(&key_ref("api.dev.MISSING")), None);
+
+        let err = config
+            .set_in(Layer::Project, key_ref("web.PORT"), value("1"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::OutsideProject(key) if key == "web.PORT"));
+        assert!(matches!(
+            config.remove_from(Layer::Project, &key_ref("PORT")),
+            Err(ConfigError::OutsideProject(_))
+        ));
+
+        // keys are written back relative to the project
+        config
+            .set_in(Layer::Project, key_ref("api.dev.NEW"), value("x"))
+            .await
+            .unwrap();
+        config
+            .remove_from(Layer::Project, &key_ref("api.HOST"))
+            .unwrap();
+        config.save().await.unwrap();
+        let written: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+        assert_eq!(
+            written,
+            serde_json::json!({"config": {"/": {"PORT": "80"}, "/dev": {"PORT": "8080", "NEW": "x"}}})
+        );
+        let leftovers = std::fs::read_dir(&dir)
+            .unwrap()
+            .filter(|e| {
+                e.as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .ends_with(".tmp")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+
+        let mut config = Config::load(dir.join("config.json")).await.unwrap();
+        config.load_project_file(file, "api").unwrap();
+        assert_eq!(
+            config.get(&key_ref("api.dev.NEW")).await.unwrap().unwrap(),
+            "x"
+        );
+        assert_eq!(
+            config.layer_of(&key_ref("api.dev.NEW")),
+            Some(Layer::Project)
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn vaults_are_loaded_concurrently() {
+        let dir = temp_dir("concurrent");
+        let mut config = Config::load(dir.join("config.json")).await.unwrap();
+        // each load waits for the other one, they fail if they run one after the other
+        for (name, other) in [("first", "second"), ("second", "first")] {
+            let kind = counting_vault(&dir, name, Some(other));
+            config.add_vault(name.to_string(), kind).unwrap();
+            config.config.set(
+                key_ref(&format!("app.{}", name.to_uppercase())),
+                ConfigValue::Secret(name.to_string(), key_ref("KEY")),
+            );
+        }
+        let values = config.get_all(Path::new("/app")).await.unwrap();
+        assert_eq!(values["FIRST"], "first");
+        assert_eq!(values["SECOND"], "second");
+        assert_eq!(loads(&dir), ["first", "second"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn environments_are_declared_used_and_overridden() {
+        let dir = temp_dir("envs");
+        let path = dir.join("config.json");
+        ConfigFileData::default().write(&path).unwrap();
+        let env = |context: &str| Environment {
+            context: PathBuf::from(context),
+            vault: None,
+            protected: false,
+        };
+        let mut config = Config::load(path.clone()).await.unwrap();
+        assert!(config.active_env("api").unwrap().is_none());
+        config
+            .create_env("api", String::from("dev"), env("development"))
+            .unwrap();
+        config
+            .create_env("api", String::from("prod"), env("production"))
+            .unwrap();
+        assert!(matches!(
+            config.create_env("api", String::from("dev"), env("other")),
+            Err(ConfigError::EnvAlreadyExists(_))
+        ));
+        assert!(matches!(
+            config.use_env("web", "dev"),
+            Err(ConfigError::EnvNotFound(..))
+        ));
+        assert!(matches!(
+            config.use_env("api", "staging"),
+            Err(ConfigError::EnvNotFound(..))
+        ));
+        config.use_env("api", "dev").unwrap();
+        config.save().await.unwrap();
+
+        let mut config = Config::load(path.clone()).await.unwrap();
+        let (name, env, overridden) = config.active_env("api").unwrap().unwrap();
+        assert_eq!(
+            (name, env.context.as_path(), overridden),
+            ("dev", Path::new("development"), false)
+        );
+        assert!(config.active_env("web").unwrap().is_none());
+        config.set_env_override(Some(String::from("prod")));
+        let (name, _, overridden) = config.active_env("api").unwrap().unwrap();
+        assert_eq!((name, overridden), ("prod", true));
+        config.set_env_override(Some(String::from("staging")));
+        assert!(config.active_env("api").is_err());
+        config.set_env_override(None);
+
+        assert_eq!(config.unuse_env("api").as_deref(), Some("dev"));
+        assert_eq!(config.unuse_env("api"), None);
+        assert!(config.active_env("api").unwrap().is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn protected_vaults_are_found_through_references() {
+        let dir = temp_dir("required");
+        let mut config = Config::load(dir.join("config.json")).await.unwrap();
+        for name in ["prod", "dev"] {
+            config
+                .add_vault(name.to_string(), counting_vault(&dir, name, None))
+                .unwrap();
+        }
+        assert!(!config.has_protected());
+        config.protect_vault("prod", true).unwrap();
+        assert!(config.has_protected());
+        config.config.set(
+            key_ref("app.TOKEN"),
+            ConfigValue::Secret(String::from("dev"), key_ref("KEY")),
+        );
+        config.config.set(
+            key_ref("app.URL"),
+            ConfigValue::from_value("https://${prod::KEY}@host".into()),
+        );
+        config.config.set(
+            key_ref("app.dev.URL2"),
+            ConfigValue::from_value("${URL}".into()),
+        );
+
+        let path = Path::new("/app");
+        let all = config.required_vaults(path, None).unwrap();
+        assert_eq!(Vec::from_iter(all), ["dev", "prod"]);
+        let token = config.required_vaults(path, Some("TOKEN")).unwrap();
+        assert_eq!(Vec::from_iter(token), ["dev"]);
+        // references are followed, from the context the key is resolved in
+        let url = config
+            .required_vaults(Path::new("/app/dev"), Some("URL2"))
+            .unwrap();
+        assert_eq!(Vec::from_iter(url), ["prod"]);
+        assert!(config.is_protected_vault("prod"));
+        assert!(!config.is_protected_path(path));
+        assert!(loads(&dir).is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn single_secrets_are_read_with_get() {
+        let dir = temp_dir("fetch");
+        let ops = dir.join("ops");
+        let script = format!(
+            r#"read req; case "$req" in
+                *'"get"'*) echo get >> {ops}; echo '{{"version": 1, "value": "fetched"}}';;
+                *) echo load >> {ops}; echo '{{"version": 1, "values": {{"A": "loaded", "B": "loaded"}}, "operations": ["get"]}}';;
+            esac"#,
+            ops = ops.display()
+        );
+        let info = ExecVault::info(String::from("sh"), vec![String::from("-c"), script]);
+        // created like `secret create --exec`, which records the operations of the plugin
+        let kind = ExecVault::from_info(&info).await.unwrap().to_vault_kind();
+        std::fs::remove_file(&ops).unwrap();
+        let read_ops = || std::fs::read_to_string(&ops).unwrap_or_default();
+
+        let mut config = Config::load(dir.join("config.json")).await.unwrap();
+        config.add_vault(String::from("plugin"), kind).unwrap();
+        let value = |v: &str| ConfigValue::from_value(v.into());
+        config
+            .config
+            .set(key_ref("app.URL"), value("x${plugin::A}"));
+        config
+            .config
+            .set(key_ref("app.BOTH"), value("${plugin::A}${plugin::B}"));
+
+        let url = config.get(&key_ref("app.URL")).await.unwrap().unwrap();
+        assert_eq!(url, "xfetched");
+        let secret = config.get_secret("plugin", &key_ref("A")).await.unwrap();
+        assert_eq!(secret.unwrap(), "fetched");
+        assert_eq!(read_ops(), "get\nget\n");
+        // several keys of the same vault load it, once
+        let both = config.get(&key_ref("app.BOTH")).await.unwrap().unwrap();
+        assert_eq!(both, "loadedloaded");
+        let url = config.get(&key_ref("app.URL")).await.unwrap().unwrap();
+        assert_eq!(url, "xloaded");
+        assert_eq!(read_ops(), "get\nget\nload\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
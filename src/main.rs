@@ -3,8 +3,9 @@ use std::path::PathBuf;
 use clap::{Parser, Subcommand};
 use secrets_manager::{
     commands::{
-        get_config_path, handle_config, handle_config_migration, handle_secrets, init_config,
-        ConfigCLI, VaultCli,
+        effective_context, get_config_path, handle_config, handle_config_migration,
+        handle_context_shell, handle_env, handle_run, handle_secrets, init_config, resolve_project,
+        saved_context_warning, ConfigCLI, EnvCli, RunCli, VaultCli, CONTEXT_ENV,
     },
     Config,
 };
@@ -18,16 +19,42 @@ struct Cli {
     /// config_dir path or the one passed with --config-dir
     #[arg(long)]
     config_file: Option<PathBuf>,
+    #[command(flatten)]
+    global: GlobalArgs,
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Flags applied to the config of every command
+#[derive(clap::Args)]
+struct GlobalArgs {
+    /// Serve vaults from the local cache only, see `secret cache` and `secret refresh`
+    #[arg(long, default_value_t = false)]
+    offline: bool,
+    /// Environment of the project to use for this command, instead of the current one
+    #[arg(long)]
+    env: Option<String>,
+    /// Context to use for this command only, instead of the saved one
+    #[arg(long, env = CONTEXT_ENV)]
+    context: Option<PathBuf>,
+    /// Write to or reveal protected contexts and vaults without asking for confirmation
+    #[arg(long, default_value_t = false)]
+    yes_i_mean_prod: bool,
+    /// Print more warnings, e.g about how the project was found
+    #[arg(long, short, default_value_t = false)]
+    verbose: bool,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Manage configurations
     Config(ConfigCLI),
     /// Manage vault secrets
     Secret(VaultCli),
+    /// Run a command with the config of the current context as its environment
+    Run(RunCli),
+    /// Manage the environments of the current project
+    Env(EnvCli),
     /// Manage the current context
     Context {
         #[command(subcommand)]
@@ -45,6 +72,25 @@ enum ContextCommands {
     Set { context: PathBuf },
     /// Empties the current context
     Reset,
+    /// Start a shell where every command uses the given context, without saving it
+    Shell {
+        /// Context to pin, defaults to the current one
+        context: Option<PathBuf>,
+    },
+}
+
+async fn load_config(
+    path: PathBuf,
+    global: GlobalArgs,
+) -> Result<Config, Box<dyn std::error::Error>> {
+    init_config(&path).await?;
+    let mut config = Config::load(path).await?;
+    config.set_offline(global.offline);
+    config.set_verbose(global.verbose);
+    config.set_env_override(global.env);
+    config.set_confirmed(global.yes_i_mean_prod);
+    config.set_context_override(global.context);
+    Ok(config)
 }
 
 #[tokio::main]
@@ -52,17 +98,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let res: Result<(), Box<dyn std::error::Error>> = async {
         let cli = Cli::parse();
         let config_path = get_config_path(cli.config_dir, cli.config_file)?;
+        let global = cli.global;
         match cli.command {
             Commands::Config(cli) => {
-                init_config(&config_path)?;
-                let config = Config::load(config_path).await?;
+                let config = load_config(config_path, global).await?;
                 handle_config(config, cli).await?;
             }
             Commands::Secret(cli) => {
-                init_config(&config_path)?;
-                let config = Config::load(config_path).await?;
+                let config = load_config(config_path, global).await?;
                 handle_secrets(config, cli).await?;
             }
+            Commands::Env(cli) => {
+                let config = load_config(config_path, global).await?;
+                handle_env(config, cli).await?;
+            }
+            Commands::Run(cli) => {
+                let config = load_config(config_path, global).await?;
+                let code = handle_run(config, cli).await?;
+                std::process::exit(code);
+            }
             Commands::Migrate { destination } => {
                 let new_path = destination.unwrap_or(config_path.clone());
                 handle_config_migration(&config_path, new_path).await?;
@@ -70,29 +124,64 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             Commands::Context {
                 command: ContextCommands::Set { context },
             } => {
-                init_config(&config_path)?;
-                let mut config = Config::load(config_path).await?;
+                let mut config = load_config(config_path, global).await?;
                 config.set_current_context(context);
+                let warning = saved_context_warning(&config)?;
                 config.save().await?;
+                if let Some(warning) = warning {
+                    eprintln!("warning: {}", warning);
+                }
             }
             Commands::Context {
                 command: ContextCommands::Get,
             } => {
-                init_config(&config_path)?;
-                let config = Config::load(config_path).await?;
-                println!(
-                    "current context: {}",
-                    config.get_current_context().display()
-                );
+                let config = load_config(config_path, global).await?;
+                let project = resolve_project(&config, None)?;
+                if config.context_override().is_some() {
+                    println!(
+                        "current context: {} (from --context or {})",
+                        config.get_current_context().display(),
+                        CONTEXT_ENV
+                    );
+                } else if let Some((name, env, _)) = config.active_env(&project.id)? {
+                    println!(
+                        "current context: {} (from environment {}, see `env unuse`)",
+                        env.context.display(),
+                        name
+                    );
+                } else {
+                    println!(
+                        "current context: {}",
+                        config.get_current_context().display()
+                    );
+                }
                 config.save().await?;
             }
             Commands::Context {
                 command: ContextCommands::Reset,
             } => {
-                init_config(&config_path)?;
-                let mut config = Config::load(config_path).await?;
+                let mut config = load_config(config_path, global).await?;
                 config.set_current_context(PathBuf::new());
+                let warning = saved_context_warning(&config)?;
                 config.save().await?;
+                if let Some(warning) = warning {
+                    eprintln!("warning: {}", warning);
+                }
+            }
+            Commands::Context {
+                command: ContextCommands::Shell { context },
+            } => {
+                let config = load_config(config_path, global).await?;
+                let context = match context {
+                    Some(context) => context,
+                    // the context commands of the current project use, with its environment
+                    None => {
+                        let project = resolve_project(&config, None)?;
+                        effective_context(&config, &project)?.to_path_buf()
+                    }
+                };
+                let code = handle_context_shell(config, context).await?;
+                std::process::exit(code);
             }
         }
         Ok(())
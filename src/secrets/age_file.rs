@@ -0,0 +1,290 @@
+use std::{
+    io::{BufReader, Read, Write},
+    path::PathBuf,
+};
+
+use age::{
+    armor::{ArmoredReader, ArmoredWriter, Format},
+    Decryptor, Encryptor, Identity, IdentityFile, Recipient,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{write_atomically, Configuration};
+
+use super::{VaultError, VaultKind, VaultTrait};
+
+/// Environment variable pointing to the identity used to decrypt, when the vault does not set one
+pub const IDENTITY_ENV: &str = "SECRETS_MANAGER_AGE_IDENTITY";
+
+#[derive(thiserror::Error, Debug)]
+pub enum AgeError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Encoding(#[from] serde_json::Error),
+    #[error(transparent)]
+    Encrypt(#[from] age::EncryptError),
+    #[error(transparent)]
+    Decrypt(#[from] age::DecryptError),
+    #[error(
+        "Invalid recipient {0}, expected an age public key or an ssh-ed25519/ssh-rsa public key"
+    )]
+    InvalidRecipient(String),
+    #[error("No identity found, pass --identity or set {IDENTITY_ENV}")]
+    MissingIdentity,
+    #[error("Identity {0} is not supported, passphrase protected SSH keys cannot be used")]
+    UnsupportedIdentity(String),
+    #[error("At least one recipient is required")]
+    NoRecipients,
+    #[error("{0} is not a recipient")]
+    NotRecipient(String),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AgeFileInfo {
+    path: PathBuf,
+    /// age identity file or SSH private key used to decrypt the file
+    #[serde(default)]
+    identity: Option<PathBuf>,
+}
+
+/// Content of the vault file, the recipients are kept in clear so the file can be re-encrypted
+/// by any of them and reviewed in the repository it is committed to
+#[derive(Serialize, Deserialize)]
+struct AgeFileData {
+    recipients: Vec<String>,
+    data: String,
+}
+
+/// Stores the whole configuration in a file encrypted to a list of age or SSH public keys
+pub struct AgeFileVault {
+    info: AgeFileInfo,
+    recipients: Vec<String>,
+    secret_value: Configuration<String>,
+}
+
+#[async_trait::async_trait]
+impl VaultTrait for AgeFileVault {
+    fn get(&self) -> &Configuration<String> {
+        &self.secret_value
+    }
+
+    fn get_mut(&mut self) -> &mut Configuration<String> {
+        &mut self.secret_value
+    }
+
+    fn to_vault_kind(&self) -> VaultKind {
+        VaultKind::AgeFile(self.info.clone())
+    }
+
+    async fn save(&mut self) -> Result<(), VaultError> {
+        self.save_file()?;
+        Ok(())
+    }
+}
+
+impl AgeFileVault {
+    /// Opens the file at `path`, or creates it encrypted to `recipients` if it does not exist
+    pub fn create(
+        path: PathBuf,
+        identity: Option<PathBuf>,
+        recipients: Vec<String>,
+    ) -> Result<Self, AgeError> {
+        let info = AgeFileInfo {
+            path: std::path::absolute(path)?,
+            identity: identity.map(std::path::absolute).transpose()?,
+        };
+        if info.path.exists() {
+            return Self::from_info(&info);
+        }
+        let mut res = Self {
+            info,
+            recipients: Vec::new(),
+            secret_value: Configuration::new(),
+        };
+        for recipient in recipients {
+            res.add_recipient(recipient)?;
+        }
+        res.save_file()?;
+        Ok(res)
+    }
+
+    pub fn from_info(info: &AgeFileInfo) -> Result<Self, AgeError> {
+        let file: AgeFileData = serde_json::from_reader(std::fs::File::open(&info.path)?)?;
+        let identities = Self::identities(info)?;
+        let decryptor = Decryptor::new(ArmoredReader::new(file.data.as_bytes()))?;
+        let mut reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref()))?;
+        let mut plaintext = String::new();
+        reader.read_to_string(&mut plaintext)?;
+        Ok(Self {
+            info: info.clone(),
+            recipients: file.recipients,
+            secret_value: serde_json::from_str(&plaintext)?,
+        })
+    }
+
+    pub fn recipients(&self) -> &[String] {
+        &self.recipients
+    }
+
+    /// Adds a recipient, the file is re-encrypted on save
+    pub fn add_recipient(&mut self, recipient: String) -> Result<bool, AgeError> {
+        let recipient = recipient.trim().to_string();
+        parse_recipient(&recipient)?;
+        if self.recipients.contains(&recipient) {
+            return Ok(false);
+        }
+        self.recipients.push(recipient);
+        Ok(true)
+    }
+
+    /// Removes a recipient, the file is re-encrypted on save.
+    /// Secrets it could read should be rotated, older versions of the file stay readable to it
+    pub fn remove_recipient(&mut self, recipient: &str) -> Result<(), AgeError> {
+        let remaining: Vec<String> = self
+            .recipients
+            .iter()
+            .filter(|r| *r != recipient.trim())
+            .cloned()
+            .collect();
+        if remaining.len() == self.recipients.len() {
+            return Err(AgeError::NotRecipient(recipient.trim().to_string()));
+        }
+        if remaining.is_empty() {
+            return Err(AgeError::NoRecipients);
+        }
+        self.recipients = remaining;
+        Ok(())
+    }
+
+    /// Encrypts the content again, with a new file key, for the current recipients
+    pub fn reencrypt(&mut self) -> Result<(), AgeError> {
+        self.save_file()
+    }
+
+    fn identities(info: &AgeFileInfo) -> Result<Vec<Box<dyn Identity>>, AgeError> {
+        let path = match info.identity {
+            Some(ref path) => path.clone(),
+            None => match std::env::var_os(IDENTITY_ENV) {
+                Some(path) => PathBuf::from(path),
+                None => {
+                    let home = std::env::var_os("HOME").ok_or(AgeError::MissingIdentity)?;
+                    let ssh = PathBuf::from(home).join(".ssh");
+                    ["id_ed25519", "id_rsa"]
+                        .into_iter()
+                        .map(|name| ssh.join(name))
+                        .find(|path| path.exists())
+                        .ok_or(AgeError::MissingIdentity)?
+                }
+            },
+        };
+        let content = std::fs::read_to_string(&path)?;
+        let name = path.display().to_string();
+        if content.trim_start().starts_with("-----BEGIN") {
+            let identity = age::ssh::Identity::from_buffer(
+                BufReader::new(content.as_bytes()),
+                Some(name.clone()),
+            )?;
+            if !matches!(identity, age::ssh::Identity::Unencrypted(_)) {
+                return Err(AgeError::UnsupportedIdentity(name));
+            }
+            return Ok(vec![Box::new(identity)]);
+        }
+        Ok(IdentityFile::from_buffer(BufReader::new(content.as_bytes()))?.into_identities()?)
+    }
+
+    fn save_file(&mut self) -> Result<(), AgeError> {
+        let recipients = self
+            .recipients
+            .iter()
+            .map(|r| parse_recipient(r))
+            .collect::<Result<Vec<_>, _>>()?;
+        if recipients.is_empty() {
+            return Err(AgeError::NoRecipients);
+        }
+        let encryptor = Encryptor::with_recipients(recipients.iter().map(|r| r.as_ref() as _))?;
+        let mut data = Vec::new();
+        let armor = ArmoredWriter::wrap_output(&mut data, Format::AsciiArmor)?;
+        let mut writer = encryptor.wrap_output(armor)?;
+        writer.write_all(serde_json::to_string(&self.secret_value)?.as_bytes())?;
+        writer.finish()?.finish()?;
+        let file = AgeFileData {
+            recipients: self.recipients.clone(),
+            data: String::from_utf8_lossy(&data).to_string(),
+        };
+        let mut content = serde_json::to_string_pretty(&file)?;
+        content.push('\n');
+        write_atomically(&self.info.path, content.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn parse_recipient(recipient: &str) -> Result<Box<dyn Recipient + Send>, AgeError> {
+    let invalid = || AgeError::InvalidRecipient(recipient.to_string());
+    if recipient.starts_with("ssh-") {
+        let recipient: age::ssh::Recipient = recipient.parse().map_err(|_| invalid())?;
+        Ok(Box::new(recipient))
+    } else {
+        let recipient: age::x25519::Recipient = recipient.parse().map_err(|_| invalid())?;
+        Ok(Box::new(recipient))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use age::secrecy::ExposeSecret;
+
+    use super::*;
+    use crate::KeyRef;
+
+    #[test]
+    fn recipients_can_decrypt() {
+        let dir = std::env::temp_dir().join(format!("secrets-manager-age-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let alice = age::x25519::Identity::generate();
+        let bob = age::x25519::Identity::generate();
+        for (name, identity) in [("alice.key", &alice), ("bob.key", &bob)] {
+            std::fs::write(dir.join(name), identity.to_string().expose_secret()).unwrap();
+        }
+        let path = dir.join("vault.json");
+        let key_ref: KeyRef = "dev.PASS".parse().unwrap();
+
+        let mut vault = AgeFileVault::create(
+            path.clone(),
+            Some(dir.join("alice.key")),
+            vec![alice.to_public().to_string()],
+        )
+        .unwrap();
+        vault.get_mut().set(key_ref.clone(), "hunter2");
+        assert!(vault.add_recipient(bob.to_public().to_string()).unwrap());
+        vault.reencrypt().unwrap();
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("hunter2"));
+
+        let as_bob = AgeFileInfo {
+            path: path.clone(),
+            identity: Some(dir.join("bob.key")),
+        };
+        let mut vault = AgeFileVault::from_info(&as_bob).unwrap();
+        assert_eq!(vault.get().get(&key_ref), Some(&String::from("hunter2")));
+
+        vault
+            .remove_recipient(&alice.to_public().to_string())
+            .unwrap();
+        vault.reencrypt().unwrap();
+        let as_alice = AgeFileInfo {
+            path,
+            identity: Some(dir.join("alice.key")),
+        };
+        assert!(AgeFileVault::from_info(&as_alice).is_err());
+        assert!(matches!(
+            vault.remove_recipient(&alice.to_public().to_string()),
+            Err(AgeError::NotRecipient(_))
+        ));
+        assert!(matches!(
+            vault.remove_recipient(&bob.to_public().to_string()),
+            Err(AgeError::NoRecipients)
+        ));
+        assert!(vault.add_recipient(String::from("not a key")).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
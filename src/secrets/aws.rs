@@ -1,34 +1,70 @@
-use aws_config::meta::region::RegionProviderChain;
+use aws_config::{meta::region::RegionProviderChain, SdkConfig};
 use aws_sdk_secretsmanager::{
     operation::{create_secret::CreateSecretOutput, get_secret_value::GetSecretValueOutput},
+    primitives::DateTimeFormat,
     types::{Filter, FilterNameStringType},
     Client,
 };
 use serde::{Deserialize, Serialize};
 
-use crate::Configuration;
+use crate::{Configuration, KeyRef};
 
-use super::{VaultError, VaultKind, VaultTrait};
+use super::{key_from_name, key_name, VaultError, VaultKind, VaultTrait};
 
 #[derive(thiserror::Error, Debug)]
 pub enum AwsError {
     #[error(transparent)]
-    Secret(#[from] aws_sdk_secretsmanager::Error),
+    Secret(Box<aws_sdk_secretsmanager::Error>),
     #[error(transparent)]
     Encoding(#[from] serde_json::Error),
+    #[error("Versions are only tracked for vaults stored in a single secret")]
+    NoHistory,
+    #[error("Version {0} not found")]
+    VersionNotFound(String),
 }
 
-fn default_profile() -> String {
+// boxed, the sdk error is large enough to bloat every `Result` it goes through
+impl From<aws_sdk_secretsmanager::Error> for AwsError {
+    fn from(e: aws_sdk_secretsmanager::Error) -> Self {
+        Self::Secret(Box::new(e))
+    }
+}
+
+pub(super) fn default_profile() -> String {
     String::from("default")
 }
 
+/// How the configuration is laid out in Secrets Manager
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub enum AwsLayout {
+    /// The whole configuration is one JSON document in a single secret
+    #[default]
+    Single,
+    /// Each key is its own secret, named `<name>/<path>/<key>`
+    PerKey,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AwsSecretInfo {
     id: String,
+    /// Name of the secret, or the name prefix with the per key layout
     name: String,
     version: String,
     #[serde(default = "default_profile")]
     profile_name: String,
+    #[serde(default)]
+    layout: AwsLayout,
+}
+
+/// A version of the secret kept by Secrets Manager
+#[derive(Debug)]
+pub struct SecretVersion {
+    pub id: String,
+    pub created: Option<String>,
+    /// Staging labels, e.g AWSCURRENT or AWSPREVIOUS
+    pub stages: Vec<String>,
+    /// Keys added, removed or modified compared to the previous version
+    pub changed: Vec<KeyRef>,
 }
 
 #[derive(Debug)]
@@ -36,6 +72,10 @@ pub struct AwsSecretVault {
     client: Client,
     secret_info: AwsSecretInfo,
     secret_value: Configuration<String>,
+    /// Version of the loaded content, `secret_info.version` is the last one saved from here
+    current_version: String,
+    /// Value of the secret at `secret_info.version`, used to merge concurrent edits
+    base_value: Configuration<String>,
 }
 
 #[async_trait::async_trait]
@@ -48,17 +88,50 @@ impl VaultTrait for AwsSecretVault {
         &mut self.secret_value
     }
 
-    fn into_vault_kind(&self) -> VaultKind {
+    fn to_vault_kind(&self) -> VaultKind {
         VaultKind::AwsSecretManager(self.secret_info.clone())
     }
+
+    fn version(&self) -> Option<String> {
+        match self.secret_info.layout {
+            AwsLayout::Single => Some(self.current_version.clone()),
+            AwsLayout::PerKey => None,
+        }
+    }
+
     async fn save(&mut self) -> Result<(), VaultError> {
+        if self.secret_info.layout == AwsLayout::PerKey {
+            self.save_keys().await?;
+            return Ok(());
+        }
+        let current = Self::get_secret_by_arn(&self.client, self.secret_id())
+            .await
+            .map_err(AwsError::from)?;
+        if current.version_id().unwrap_or_default() != self.secret_info.version {
+            let theirs = Self::parse_secret(&current)?;
+            self.merge_edits(&theirs)?;
+        }
         self.save_secret().await?;
         Ok(())
     }
 }
 
 impl AwsSecretVault {
-    pub async fn create(secret_name: String, profile_name: String) -> Result<Self, AwsError> {
+    pub async fn create(
+        secret_name: String,
+        profile_name: String,
+        layout: AwsLayout,
+    ) -> Result<Self, AwsError> {
+        if layout == AwsLayout::PerKey {
+            let info = AwsSecretInfo {
+                id: secret_name.clone(),
+                name: secret_name,
+                profile_name,
+                version: String::new(),
+                layout,
+            };
+            return Self::from_info(&info).await;
+        }
         let client = Self::make_client(&profile_name).await;
         let (info, secret_value) =
             if let Some(arn) = Self::get_secret_by_name(&client, &secret_name).await? {
@@ -68,12 +141,9 @@ impl AwsSecretVault {
                     name: secret_name,
                     profile_name,
                     version: secret.version_id().unwrap_or_default().to_string(),
+                    layout,
                 };
-                if let Some(value_raw) = secret.secret_string() {
-                    (info, serde_json::from_str(value_raw)?)
-                } else {
-                    (info, Configuration::new())
-                }
+                (info, Self::parse_secret(&secret)?)
             } else {
                 let secret = Self::create_secret(&client, &secret_name).await?;
                 let arn = secret.arn().unwrap().to_string();
@@ -82,13 +152,16 @@ impl AwsSecretVault {
                     name: secret_name,
                     profile_name,
                     version: secret.version_id().unwrap_or_default().to_string(),
+                    layout,
                 };
                 (info, Configuration::new())
             };
 
         let mut res = Self {
             client,
+            current_version: info.version.clone(),
             secret_info: info,
+            base_value: secret_value.clone(),
             secret_value,
         };
         res.save_secret().await?;
@@ -98,23 +171,270 @@ impl AwsSecretVault {
     async fn save_secret(&mut self) -> Result<(), AwsError> {
         let writer = serde_json::to_string_pretty(&self.secret_value)?;
         self.secret_info.version = self.update_secret(writer).await?;
+        self.current_version = self.secret_info.version.clone();
+        self.base_value = self.secret_value.clone();
         Ok(())
     }
     pub async fn from_info(info: &AwsSecretInfo) -> Result<Self, AwsError> {
         let client = Self::make_client(&info.profile_name).await;
-        let value = Self::from_secret_arn(&client, &info.id).await?;
+        Self::with_client(client, info).await
+    }
+
+    async fn with_client(client: Client, info: &AwsSecretInfo) -> Result<Self, AwsError> {
+        if info.layout == AwsLayout::PerKey {
+            let value = Self::get_keys(&client, &info.name).await?;
+            return Ok(Self {
+                client,
+                secret_info: info.clone(),
+                current_version: String::new(),
+                base_value: value.clone(),
+                secret_value: value,
+            });
+        }
+        let secret = Self::get_secret_by_arn(&client, &info.id).await?;
+        let value = Self::parse_secret(&secret)?;
+        let current_version = secret.version_id().unwrap_or_default().to_string();
+        let mut secret_info = info.clone();
+        // edits are made on top of the version last saved from here, changes made in AWS
+        // since then are merged on save, or reported as conflicts
+        let base_value = if info.version.is_empty() || info.version == current_version {
+            secret_info.version = current_version.clone();
+            value.clone()
+        } else {
+            match Self::value_at(&client, &info.id, &info.version).await {
+                Ok(base) => base,
+                // versions without a staging label are eventually deleted by AWS
+                Err(AwsError::VersionNotFound(_)) => {
+                    secret_info.version = current_version.clone();
+                    value.clone()
+                }
+                Err(e) => return Err(e),
+            }
+        };
         Ok(Self {
             client,
-            secret_info: info.clone(),
+            secret_info,
+            current_version,
+            base_value,
             secret_value: value,
         })
     }
 
-    async fn from_secret_arn(
+    /// Reads every secret under `prefix`, with the per key layout
+    async fn get_keys(
+        client: &Client,
+        prefix: &str,
+    ) -> Result<Configuration<String>, aws_sdk_secretsmanager::Error> {
+        let mut value = Configuration::new();
+        let mut pages = client
+            .batch_get_secret_value()
+            .filters(
+                Filter::builder()
+                    .key(FilterNameStringType::Name)
+                    .values(format!("{}/", prefix))
+                    .build(),
+            )
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.next().await {
+            for secret in page?.secret_values() {
+                let (Some(name), Some(secret_value)) = (secret.name(), secret.secret_string())
+                else {
+                    continue;
+                };
+                if let Some(key_ref) = key_from_name(prefix, name) {
+                    value.set(key_ref, secret_value);
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    /// Merges the edits made here since `base_value` with `theirs`, the edits made in AWS
+    fn merge_edits(&mut self, theirs: &Configuration<String>) -> Result<(), VaultError> {
+        self.secret_value = self
+            .secret_value
+            .merge(&self.base_value, theirs)
+            .map_err(|keys| {
+                VaultError::Conflict(
+                    self.secret_info.name.clone(),
+                    keys.iter().map(|k| k.to_string()).collect(),
+                )
+            })?;
+        Ok(())
+    }
+
+    /// Writes the keys changed since the vault was loaded, with the per key layout.
+    /// Secrets have no common version, keys edited in AWS in the meantime are merged
+    /// against the keys as they were loaded
+    async fn save_keys(&mut self) -> Result<(), VaultError> {
+        let theirs = Self::get_keys(&self.client, &self.secret_info.name)
+            .await
+            .map_err(AwsError::from)?;
+        self.merge_edits(&theirs)?;
+        // keys are compared as stored, a value inherited from a parent path is a secret of its own
+        for key_ref in self.secret_value.changed_keys(&theirs) {
+            let name = key_name(&self.secret_info.name, &key_ref);
+            let result = match self.secret_value.get_exact(&key_ref) {
+                Some(value) if theirs.get_exact(&key_ref).is_none() => {
+                    self.create_key(&name, value).await
+                }
+                Some(value) => self
+                    .client
+                    .put_secret_value()
+                    .secret_id(&name)
+                    .secret_string(value)
+                    .send()
+                    .await
+                    .map(|_| ())
+                    .map_err(Into::into),
+                None => self
+                    .client
+                    .delete_secret()
+                    .secret_id(&name)
+                    .send()
+                    .await
+                    .map(|_| ())
+                    .map_err(Into::into),
+            };
+            result.map_err(AwsError::from)?;
+        }
+        self.base_value = self.secret_value.clone();
+        Ok(())
+    }
+
+    /// Creates the secret of a key, or restores it if it was removed before
+    async fn create_key(
+        &self,
+        name: &str,
+        value: &str,
+    ) -> Result<(), aws_sdk_secretsmanager::Error> {
+        let created = self
+            .client
+            .create_secret()
+            .name(name)
+            .secret_string(value)
+            .send()
+            .await;
+        match created {
+            Ok(_) => return Ok(()),
+            Err(err) => match err.as_service_error() {
+                Some(e) if e.is_resource_exists_exception() => {}
+                // the secret was removed before and is scheduled for deletion
+                Some(e) if e.is_invalid_request_exception() => {
+                    self.client.restore_secret().secret_id(name).send().await?;
+                }
+                _ => return Err(err.into()),
+            },
+        }
+        self.client
+            .put_secret_value()
+            .secret_id(name)
+            .secret_string(value)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Versions of the secret, oldest first. Only versions where `key` changed when it is set
+    pub async fn history(&self, key: Option<&KeyRef>) -> Result<Vec<SecretVersion>, AwsError> {
+        if self.secret_info.layout != AwsLayout::Single {
+            return Err(AwsError::NoHistory);
+        }
+        let mut entries = Vec::new();
+        let mut pages = self
+            .client
+            .list_secret_version_ids()
+            .secret_id(self.secret_id())
+            .include_deprecated(true)
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(aws_sdk_secretsmanager::Error::from)?;
+            entries.extend(page.versions.unwrap_or_default());
+        }
+        entries.sort_by_key(|v| v.created_date().map(|d| (d.secs(), d.subsec_nanos())));
+
+        let mut res = Vec::new();
+        let mut previous = Configuration::new();
+        for entry in entries {
+            let Some(id) = entry.version_id() else {
+                continue;
+            };
+            let value = Self::value_at(&self.client, self.secret_id(), id).await?;
+            let changed = value.changed_keys(&previous);
+            previous = value;
+            if key.is_some_and(|k| !changed.contains(k)) {
+                continue;
+            }
+            res.push(SecretVersion {
+                id: id.to_string(),
+                created: entry
+                    .created_date()
+                    .and_then(|d| d.fmt(DateTimeFormat::DateTime).ok()),
+                stages: entry.version_stages().to_vec(),
+                changed,
+            });
+        }
+        Ok(res)
+    }
+
+    /// Writes the content of a previous version as a new current version, returns its id.
+    /// Keys changed in AWS since the stored version, and that the rollback would change again,
+    /// are reported as conflicts
+    pub async fn rollback(&mut self, version: &str) -> Result<String, VaultError> {
+        if self.secret_info.layout != AwsLayout::Single {
+            return Err(AwsError::NoHistory.into());
+        }
+        let restored = Self::value_at(&self.client, self.secret_id(), version).await?;
+        let current = Self::get_secret_by_arn(&self.client, self.secret_id())
+            .await
+            .map_err(AwsError::from)?;
+        if current.version_id().unwrap_or_default() != self.secret_info.version {
+            let theirs = Self::parse_secret(&current)?;
+            let conflicts: Vec<String> = theirs
+                .changed_keys(&self.base_value)
+                .into_iter()
+                .filter(|k| restored.get_exact(k) != theirs.get_exact(k))
+                .map(|k| k.to_string())
+                .collect();
+            if !conflicts.is_empty() {
+                return Err(VaultError::Conflict(
+                    self.secret_info.name.clone(),
+                    conflicts,
+                ));
+            }
+        }
+        self.secret_value = restored;
+        self.save_secret().await?;
+        Ok(self.secret_info.version.clone())
+    }
+
+    async fn value_at(
         client: &Client,
-        secret_arn: &str,
+        id: &str,
+        version: &str,
     ) -> Result<Configuration<String>, AwsError> {
-        let secret = Self::get_secret_by_arn(client, secret_arn).await?;
+        let secret = client
+            .get_secret_value()
+            .secret_id(id)
+            .version_id(version)
+            .send()
+            .await;
+        match secret {
+            Ok(secret) => Self::parse_secret(&secret),
+            Err(err)
+                if err
+                    .as_service_error()
+                    .is_some_and(|e| e.is_resource_not_found_exception()) =>
+            {
+                Err(AwsError::VersionNotFound(version.to_string()))
+            }
+            Err(err) => Err(aws_sdk_secretsmanager::Error::from(err).into()),
+        }
+    }
+
+    fn parse_secret(secret: &GetSecretValueOutput) -> Result<Configuration<String>, AwsError> {
         let secret_value = if let Some(secret_str) = secret.secret_string() {
             serde_json::from_str(secret_str)?
         } else {
@@ -132,13 +452,17 @@ impl AwsSecretVault {
     }
 
     async fn make_client(profile_name: &str) -> Client {
+        Client::new(&Self::sdk_config(profile_name).await)
+    }
+
+    /// Shared AWS configuration for `profile_name`, falling back to eu-west-1 when no region is set
+    pub(super) async fn sdk_config(profile_name: &str) -> SdkConfig {
         let region_provider = RegionProviderChain::default_provider().or_else("eu-west-1");
-        let config = aws_config::defaults(aws_config::BehaviorVersion::latest())
+        aws_config::defaults(aws_config::BehaviorVersion::latest())
             .profile_name(profile_name)
             .region(region_provider)
             .load()
-            .await;
-        Client::new(&config)
+            .await
     }
 
     async fn create_secret(
@@ -194,3 +518,261 @@ impl AwsSecretVault {
         Ok(response.version_id().unwrap_or_default().to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    };
+
+    use aws_sdk_secretsmanager::config::{
+        retry::RetryConfig, BehaviorVersion, Credentials, Region,
+    };
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::secrets::fake_aws::FakeAws;
+
+    #[derive(Debug, Default)]
+    struct FakeSecret {
+        /// (id, content), oldest first, the last one is AWSCURRENT
+        versions: Vec<(String, String)>,
+        deleted: bool,
+    }
+
+    /// In memory Secrets Manager, answering the operations used by the vault
+    #[derive(Debug, Default, Clone)]
+    struct FakeSecretsManager {
+        secrets: Arc<Mutex<BTreeMap<String, FakeSecret>>>,
+    }
+
+    impl FakeSecretsManager {
+        /// Writes a new version of `name`, like a teammate would, returns its id
+        fn put(&self, name: &str, content: &str) -> String {
+            let mut secrets = self.secrets.lock().unwrap();
+            let count: usize = secrets.values().map(|s| s.versions.len()).sum();
+            let id = format!("v{}", count + 1);
+            let secret = secrets.entry(name.to_string()).or_default();
+            secret.versions.push((id.clone(), content.to_string()));
+            id
+        }
+
+        fn current(&self, name: &str) -> Option<String> {
+            let secrets = self.secrets.lock().unwrap();
+            let secret = secrets.get(name).filter(|s| !s.deleted)?;
+            secret.versions.last().map(|(_, content)| content.clone())
+        }
+
+        fn client(&self) -> Client {
+            let fake = self.clone();
+            let config = aws_sdk_secretsmanager::Config::builder()
+                .behavior_version(BehaviorVersion::latest())
+                .region(Region::new("eu-west-1"))
+                .credentials_provider(Credentials::new("id", "secret", None, None, "test"))
+                .retry_config(RetryConfig::disabled())
+                .http_client(
+                    FakeAws::new(move |op, request| fake.handle(op, request)).http_client(),
+                )
+                .build();
+            Client::from_conf(config)
+        }
+
+        fn handle(&self, operation: &str, request: &Value) -> Result<Value, &'static str> {
+            let id = request["SecretId"].as_str().or(request["Name"].as_str());
+            let id = id.unwrap_or_default().to_string();
+            let content = request["SecretString"].as_str().unwrap_or_default();
+            let mut secrets = self.secrets.lock().unwrap();
+            match operation {
+                "CreateSecret" => match secrets.get(&id) {
+                    Some(secret) if secret.deleted => return Err("InvalidRequestException"),
+                    Some(_) => return Err("ResourceExistsException"),
+                    None => drop(secrets),
+                },
+                "UpdateSecret" | "PutSecretValue" => drop(secrets),
+                "GetSecretValue" => {
+                    let secret = secrets.get(&id).filter(|s| !s.deleted);
+                    let version = request["VersionId"].as_str();
+                    let (version, content) = secret
+                        .and_then(|s| match version {
+                            Some(version) => s.versions.iter().find(|(v, _)| v == version),
+                            None => s.versions.last(),
+                        })
+                        .ok_or("ResourceNotFoundException")?;
+                    return Ok(json!({
+                        "ARN": id, "Name": id, "VersionId": version, "SecretString": content,
+                    }));
+                }
+                "ListSecretVersionIds" => {
+                    let secret = secrets.get(&id).ok_or("ResourceNotFoundException")?;
+                    let last = secret.versions.len() - 1;
+                    let versions: Vec<_> = secret
+                        .versions
+                        .iter()
+                        .enumerate()
+                        .map(|(i, (version, _))| {
+                            let stages: &[&str] = if i == last { &["AWSCURRENT"] } else { &[] };
+                            json!({
+                                "VersionId": version,
+                                "VersionStages": stages,
+                                "CreatedDate": 1_700_000_000 + i,
+                            })
+                        })
+                        .collect();
+                    return Ok(json!({ "ARN": id, "Name": id, "Versions": versions }));
+                }
+                "BatchGetSecretValue" => {
+                    let prefix = request["Filters"][0]["Values"][0]
+                        .as_str()
+                        .unwrap_or_default();
+                    let values: Vec<_> = secrets
+                        .iter()
+                        .filter(|(name, s)| name.starts_with(prefix) && !s.deleted)
+                        .map(|(name, s)| {
+                            let (version, content) = s.versions.last().unwrap();
+                            json!({
+                                "ARN": name, "Name": name, "VersionId": version, "SecretString": content,
+                            })
+                        })
+                        .collect();
+                    return Ok(json!({ "SecretValues": values, "Errors": [] }));
+                }
+                "DeleteSecret" | "RestoreSecret" => {
+                    let secret = secrets.get_mut(&id).ok_or("ResourceNotFoundException")?;
+                    secret.deleted = operation == "DeleteSecret";
+                    return Ok(json!({ "ARN": id, "Name": id }));
+                }
+                _ => panic!("unexpected operation {}", operation),
+            }
+            if operation != "CreateSecret" && self.current(&id).is_none() {
+                return Err("ResourceNotFoundException");
+            }
+            let version = self.put(&id, content);
+            Ok(json!({ "ARN": id, "Name": id, "VersionId": version }))
+        }
+    }
+
+    fn info(name: &str, version: &str, layout: AwsLayout) -> AwsSecretInfo {
+        AwsSecretInfo {
+            id: name.to_string(),
+            name: name.to_string(),
+            version: version.to_string(),
+            profile_name: default_profile(),
+            layout,
+        }
+    }
+
+    fn key(key: &str) -> KeyRef {
+        key.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn edits_are_checked_against_the_stored_version() {
+        let fake = FakeSecretsManager::default();
+        let stored = fake.put("app", r#"{"/": {"A": "1", "B": "1"}}"#);
+        // changed in AWS after the version stored in the config was saved
+        fake.put("app", r#"{"/": {"A": "2", "B": "theirs"}}"#);
+
+        let info = info("app", &stored, AwsLayout::Single);
+        let mut vault = AwsSecretVault::with_client(fake.client(), &info)
+            .await
+            .unwrap();
+        assert_eq!(vault.get().get(&key("A")), Some(&String::from("2")));
+        vault.get_mut().set(key("C"), "mine");
+        vault.save().await.unwrap();
+        let saved: Configuration<String> =
+            serde_json::from_str(&fake.current("app").unwrap()).unwrap();
+        assert_eq!(saved.get(&key("B")), Some(&String::from("theirs")));
+        assert_eq!(saved.get(&key("C")), Some(&String::from("mine")));
+
+        // B changed in AWS since the stored version, and here too
+        let mut vault = AwsSecretVault::with_client(fake.client(), &info)
+            .await
+            .unwrap();
+        vault.get_mut().set(key("B"), "mine");
+        let err = vault.save().await.unwrap_err();
+        assert!(matches!(err, VaultError::Conflict(_, keys) if keys == ["B"]));
+
+        // the stored version expired, the current one becomes the base
+        let mut expired = info.clone();
+        expired.version = String::from("gone");
+        let vault = AwsSecretVault::with_client(fake.client(), &expired)
+            .await
+            .unwrap();
+        assert_eq!(vault.secret_info.version, vault.current_version);
+    }
+
+    #[tokio::test]
+    async fn per_key_edits_are_saved_exactly_and_merged() {
+        let fake = FakeSecretsManager::default();
+        for name in ["app/X", "app/dev/X", "app/Y"] {
+            fake.put(name, "1");
+        }
+        let info = info("app", "", AwsLayout::PerKey);
+        let mut vault = AwsSecretVault::with_client(fake.client(), &info)
+            .await
+            .unwrap();
+        // shadowing keys with the value of their parent are secrets of their own
+        vault.get_mut().remove(&key("dev.X"));
+        vault.get_mut().set(key("dev.Y"), "1");
+        fake.put("app/Z", "theirs");
+        vault.save().await.unwrap();
+        assert_eq!(fake.current("app/dev/X"), None);
+        assert_eq!(fake.current("app/dev/Y").unwrap(), "1");
+        assert_eq!(fake.current("app/Z").unwrap(), "theirs");
+        assert_eq!(vault.get().get_exact(&key("Z")).unwrap(), "theirs");
+
+        // Y changed in AWS since the vault was loaded, and here too
+        vault.get_mut().set(key("Y"), "mine");
+        fake.put("app/Y", "theirs");
+        let err = vault.save().await.unwrap_err();
+        assert!(matches!(err, VaultError::Conflict(_, keys) if keys == ["Y"]));
+        assert_eq!(fake.current("app/Y").unwrap(), "theirs");
+    }
+
+    #[tokio::test]
+    async fn history_lists_the_keys_changed_by_each_version() {
+        let fake = FakeSecretsManager::default();
+        fake.put("app", r#"{"/": {"A": "1"}}"#);
+        fake.put("app", r#"{"/": {"A": "1", "B": "1"}}"#);
+        fake.put("app", r#"{"/": {"A": "2", "B": "1"}}"#);
+        let vault = AwsSecretVault::with_client(fake.client(), &info("app", "", AwsLayout::Single))
+            .await
+            .unwrap();
+
+        let history = vault.history(None).await.unwrap();
+        let ids: Vec<_> = history.iter().map(|v| v.id.as_str()).collect();
+        assert_eq!(ids, ["v1", "v2", "v3"]);
+        let changed: Vec<_> = history.iter().map(|v| v.changed.clone()).collect();
+        assert_eq!(changed, [vec![key("A")], vec![key("B")], vec![key("A")]]);
+        assert_eq!(history[2].stages, ["AWSCURRENT"]);
+
+        let history = vault.history(Some(&key("B"))).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].id, "v2");
+    }
+
+    #[tokio::test]
+    async fn rollback_is_checked_against_the_stored_version() {
+        let fake = FakeSecretsManager::default();
+        let first = fake.put("app", r#"{"/": {"A": "1"}}"#);
+        let stored = fake.put("app", r#"{"/": {"A": "2", "B": "1"}}"#);
+        let mut vault =
+            AwsSecretVault::with_client(fake.client(), &info("app", &stored, AwsLayout::Single))
+                .await
+                .unwrap();
+
+        let restored = vault.rollback(&first).await.unwrap();
+        assert_eq!(vault.secret_info.version, restored);
+        let saved: Configuration<String> =
+            serde_json::from_str(&fake.current("app").unwrap()).unwrap();
+        assert_eq!(saved.get(&key("A")).unwrap(), "1");
+        assert_eq!(saved.get(&key("B")), None);
+
+        // C was added in AWS since, restoring the stored version would drop it
+        fake.put("app", r#"{"/": {"A": "1", "C": "theirs"}}"#);
+        let err = vault.rollback(&stored).await.unwrap_err();
+        assert!(matches!(err, VaultError::Conflict(_, keys) if keys == ["C"]));
+        assert!(fake.current("app").unwrap().contains("theirs"));
+    }
+}
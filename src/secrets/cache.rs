@@ -0,0 +1,254 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use securestore::{KeySource, SecretsManager};
+use serde::{Deserialize, Serialize};
+
+use crate::{replace_atomically, Configuration};
+
+use super::{VaultError, VaultKind, VaultTrait};
+
+const ENTRY: &str = "entry";
+
+#[derive(thiserror::Error, Debug)]
+pub enum CacheError {
+    #[error(transparent)]
+    Store(#[from] securestore::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Encoding(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct CacheSettings {
+    /// Seconds after which a cached vault is fetched again
+    pub ttl: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    /// Descriptor of the vault in the config, with the stored version for backends that have one
+    #[serde(default)]
+    kind: Option<VaultKind>,
+    /// Version id of the vault when it was fetched, if the backend has one
+    version: Option<String>,
+    fetched_at: u64,
+    value: Configuration<String>,
+}
+
+/// Local copies of fetched vaults, each one in its own SecureStore file encrypted with
+/// a key generated on first use. The key lives outside of `dir`, the entries alone,
+/// e.g. a copy of the cache directory, do not reveal anything
+pub struct VaultCache {
+    dir: PathBuf,
+    key_file: PathBuf,
+}
+
+impl VaultCache {
+    pub fn new(dir: PathBuf, key_file: PathBuf) -> Self {
+        Self { dir, key_file }
+    }
+
+    /// Cached copy of vault `name`, `None` if there is none, it is `max_age` old or more
+    /// or it was fetched for another descriptor, e.g a vault recreated under the same name
+    pub fn read(
+        &self,
+        name: &str,
+        kind: &VaultKind,
+        max_age: Option<Duration>,
+    ) -> Result<Option<CachedVault>, CacheError> {
+        let path = self.entry_path(name);
+        if !path.exists() || !self.key_file.exists() {
+            return Ok(None);
+        }
+        let manager = SecretsManager::load(&path, self.key_file.as_path())?;
+        let entry: CacheEntry = serde_json::from_str(&manager.get(ENTRY)?)?;
+        if !entry
+            .kind
+            .as_ref()
+            .is_some_and(|cached| same_kind(cached, kind))
+        {
+            return Ok(None);
+        }
+        let age = Duration::from_secs(now().saturating_sub(entry.fetched_at));
+        if max_age.is_some_and(|max_age| age >= max_age) {
+            return Ok(None);
+        }
+        Ok(Some(CachedVault {
+            name: name.to_string(),
+            kind: kind.clone(),
+            version: entry.version,
+            value: entry.value,
+        }))
+    }
+
+    /// Stores the content of `vault`, fetched for the descriptor `kind`
+    pub fn write(
+        &self,
+        name: &str,
+        kind: &VaultKind,
+        vault: &dyn VaultTrait,
+    ) -> Result<(), CacheError> {
+        let mut manager = self.manager()?;
+        self.create_dir()?;
+        let entry = CacheEntry {
+            kind: Some(kind.clone()),
+            version: vault.version(),
+            fetched_at: now(),
+            value: vault.get().clone(),
+        };
+        manager.set(ENTRY, serde_json::to_string(&entry)?);
+        // a crash never leaves a truncated entry behind
+        replace_atomically(&self.entry_path(name), 0o600, |tmp_path| {
+            manager.save_as(tmp_path).map_err(CacheError::from)
+        })
+    }
+
+    /// Deletes every cached vault, along with the key used to encrypt them
+    pub fn clear(&self) -> Result<(), CacheError> {
+        let res = std::fs::remove_dir_all(&self.dir);
+        if !matches!(&res, Err(e) if e.kind() == std::io::ErrorKind::NotFound) {
+            res?;
+        }
+        match std::fs::remove_file(&self.key_file) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn manager(&self) -> Result<SecretsManager, CacheError> {
+        if self.key_file.exists() {
+            return Ok(SecretsManager::new(self.key_file.as_path())?);
+        }
+        // entries encrypted with a previous key, or with the one that used to be kept
+        // among them, can not be read anymore
+        self.clear()?;
+        let manager = SecretsManager::new(KeySource::Csprng)?;
+        // only readable by the owner from its creation
+        replace_atomically(&self.key_file, 0o600, |tmp_path| {
+            manager.export_key(tmp_path).map_err(CacheError::from)
+        })?;
+        Ok(manager)
+    }
+
+    fn create_dir(&self) -> Result<(), std::io::Error> {
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(&self.dir)
+    }
+
+    /// Vault names are user provided, anything but `[A-Za-z0-9_-]` is hex encoded
+    fn entry_path(&self, name: &str) -> PathBuf {
+        let mut file = String::new();
+        for b in name.bytes() {
+            if b.is_ascii_alphanumeric() || b == b'_' || b == b'-' {
+                file.push(b as char);
+            } else {
+                file.push_str(&format!("%{:02x}", b));
+            }
+        }
+        file.push_str(".json");
+        Path::new(&self.dir).join(file)
+    }
+}
+
+/// Descriptors have no `PartialEq`, they are compared in their serialized form
+fn same_kind(a: &VaultKind, b: &VaultKind) -> bool {
+    match (serde_json::to_value(a), serde_json::to_value(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Read only vault served from the cache
+pub struct CachedVault {
+    name: String,
+    kind: VaultKind,
+    version: Option<String>,
+    value: Configuration<String>,
+}
+
+#[async_trait::async_trait]
+impl VaultTrait for CachedVault {
+    fn get(&self) -> &Configuration<String> {
+        &self.value
+    }
+
+    fn get_mut(&mut self) -> &mut Configuration<String> {
+        &mut self.value
+    }
+
+    fn to_vault_kind(&self) -> VaultKind {
+        self.kind.clone()
+    }
+
+    fn version(&self) -> Option<String> {
+        self.version.clone()
+    }
+
+    async fn save(&mut self) -> Result<(), VaultError> {
+        Err(VaultError::Offline(self.name.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{secrets::SecureStoreVault, KeyRef};
+
+    #[test]
+    fn cache_round_trip() {
+        let dir =
+            std::env::temp_dir().join(format!("secrets-manager-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut vault =
+            SecureStoreVault::create(dir.join("store.json"), Some(dir.join("store.key"))).unwrap();
+        let key_ref: KeyRef = "dev.PASS".parse().unwrap();
+        vault.get_mut().set(key_ref.clone(), "hunter2");
+        let cache = VaultCache::new(dir.join("cache"), dir.join("cache.key"));
+        let kind = vault.to_vault_kind();
+        cache.write("my vault", &kind, &vault).unwrap();
+
+        let cached = cache.read("my vault", &kind, None).unwrap().unwrap();
+        assert_eq!(cached.get().get(&key_ref), Some(&String::from("hunter2")));
+        assert!(cache.read("other", &kind, None).unwrap().is_none());
+        assert!(cache
+            .read("my vault", &kind, Some(Duration::ZERO))
+            .unwrap()
+            .is_none());
+        // a vault recreated under the same name does not use the old copy
+        let other = SecureStoreVault::create(dir.join("other.json"), Some(dir.join("other.key")))
+            .unwrap()
+            .to_vault_kind();
+        assert!(cache.read("my vault", &other, None).unwrap().is_none());
+        // the value is not stored in clear
+        let raw = std::fs::read_to_string(cache.entry_path("my vault")).unwrap();
+        assert!(!raw.contains("hunter2"));
+        assert_eq!(std::fs::read_dir(dir.join("cache")).unwrap().count(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(dir.join("cache.key"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        cache.clear().unwrap();
+        assert!(!dir.join("cache.key").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,267 @@
+use std::{collections::BTreeMap, process::Stdio};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::{Configuration, KeyRef};
+
+use super::{VaultError, VaultKind, VaultTrait};
+
+/// Version of the protocol spoken with plugins, sent with every request
+pub const PROTOCOL_VERSION: u64 = 1;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ExecError {
+    #[error("Failed to run {0}: {1}")]
+    Spawn(String, std::io::Error),
+    #[error("{0} exited with {1}: {2}")]
+    Failed(String, std::process::ExitStatus, String),
+    #[error("Invalid response from {0}: {1}")]
+    InvalidResponse(String, serde_json::Error),
+    #[error("{0} speaks protocol version {1}, version {PROTOCOL_VERSION} is required")]
+    UnsupportedVersion(String, u64),
+    #[error("{0}: {1}")]
+    Plugin(String, String),
+    #[error("Invalid key {0} returned by {1}")]
+    InvalidKey(String, String),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExecInfo {
+    /// Executable of the plugin, looked up in PATH if it is not a path
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    /// Optional operations supported by the plugin, as answered to the last `load`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    operations: Vec<String>,
+}
+
+impl ExecInfo {
+    pub fn supports(&self, operation: &str) -> bool {
+        self.operations.iter().any(|o| o == operation)
+    }
+}
+
+#[derive(Deserialize)]
+struct Response {
+    version: u64,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    values: BTreeMap<String, String>,
+    #[serde(default)]
+    value: Option<String>,
+    /// Operations supported besides `load` and `save`
+    #[serde(default)]
+    operations: Vec<String>,
+}
+
+/// Vault backed by an external executable.
+///
+/// For every operation the plugin is started with the configured arguments, it reads one JSON
+/// request on stdin and writes one JSON response on stdout. Requests are
+/// `{"version": 1, "operation": "load" | "save" | "get" | "set", ...}`:
+/// - `load` answers `{"version": 1, "values": {"path.to.KEY": "value"}, "operations": ["get", "set"]}`
+/// - `save` sends `"values"`, the whole content, which replaces what the plugin stores
+/// - `get` sends `"key"` and answers `{"version": 1, "value": "..." | null}`
+/// - `set` sends `"key"` and `"value"`, a null value removes the key
+///
+/// `get` and `set` are optional, plugins list the ones they support in `operations`, which are
+/// kept in the vault descriptor. With `get`, a single secret is read without loading the vault.
+/// Failures are reported with `{"version": 1, "error": "message"}` or a non zero exit code.
+pub struct ExecVault {
+    info: ExecInfo,
+    secret_value: Configuration<String>,
+    /// Value as loaded from the plugin, with `set` only changed keys are sent back
+    base_value: Configuration<String>,
+}
+
+#[async_trait::async_trait]
+impl VaultTrait for ExecVault {
+    fn get(&self) -> &Configuration<String> {
+        &self.secret_value
+    }
+
+    fn get_mut(&mut self) -> &mut Configuration<String> {
+        &mut self.secret_value
+    }
+
+    fn to_vault_kind(&self) -> VaultKind {
+        VaultKind::Exec(self.info.clone())
+    }
+
+    async fn save(&mut self) -> Result<(), VaultError> {
+        self.save_values().await?;
+        Ok(())
+    }
+}
+
+impl ExecVault {
+    pub fn info(command: String, args: Vec<String>) -> ExecInfo {
+        ExecInfo {
+            command,
+            args,
+            operations: Vec::new(),
+        }
+    }
+
+    pub async fn from_info(info: &ExecInfo) -> Result<Self, ExecError> {
+        let response = Self::call(info, json!({ "operation": "load" })).await?;
+        let mut value = Configuration::new();
+        for (key, secret) in response.values {
+            let key_ref: KeyRef = key
+                .parse()
+                .map_err(|_| ExecError::InvalidKey(key.clone(), info.command.clone()))?;
+            value.set(key_ref, secret);
+        }
+        Ok(Self {
+            info: ExecInfo {
+                operations: response.operations,
+                ..info.clone()
+            },
+            base_value: value.clone(),
+            secret_value: value,
+        })
+    }
+
+    /// Reads a single key with the `get` operation, without loading the vault
+    pub async fn fetch(info: &ExecInfo, key_ref: &KeyRef) -> Result<Option<String>, ExecError> {
+        let request = json!({ "operation": "get", "key": key_ref.to_string() });
+        Ok(Self::call(info, request).await?.value)
+    }
+
+    fn supports(&self, operation: &str) -> bool {
+        self.info.supports(operation)
+    }
+
+    async fn save_values(&mut self) -> Result<(), ExecError> {
+        if self.supports("set") {
+            // keys are compared as stored, a value inherited from a parent path is a key of its own
+            for key_ref in self.secret_value.changed_keys(&self.base_value) {
+                let value = self.secret_value.get_exact(&key_ref);
+                let request = json!({
+                    "operation": "set",
+                    "key": key_ref.to_string(),
+                    "value": value,
+                });
+                Self::call(&self.info, request).await?;
+            }
+        } else {
+            let values: BTreeMap<String, &String> = self
+                .secret_value
+                .keys("/")
+                .filter_map(|k| Some((k.to_string(), self.secret_value.get_exact(&k)?)))
+                .collect();
+            Self::call(&self.info, json!({ "operation": "save", "values": values })).await?;
+        }
+        self.base_value = self.secret_value.clone();
+        Ok(())
+    }
+
+    async fn call(info: &ExecInfo, mut request: Value) -> Result<Response, ExecError> {
+        request["version"] = json!(PROTOCOL_VERSION);
+        let spawn_error = |e| ExecError::Spawn(info.command.clone(), e);
+        let mut child = Command::new(&info.command)
+            .args(&info.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(spawn_error)?;
+        if let Some(mut stdin) = child.stdin.take() {
+            let mut line = request.to_string();
+            line.push('\n');
+            // the plugin may exit without reading, its exit status is reported instead
+            let _ = stdin.write_all(line.as_bytes()).await;
+        }
+        let output = child.wait_with_output().await.map_err(spawn_error)?;
+        if !output.status.success() {
+            return Err(ExecError::Failed(
+                info.command.clone(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+        let response: Response = serde_json::from_slice(&output.stdout)
+            .map_err(|e| ExecError::InvalidResponse(info.command.clone(), e))?;
+        if response.version != PROTOCOL_VERSION {
+            return Err(ExecError::UnsupportedVersion(
+                info.command.clone(),
+                response.version,
+            ));
+        }
+        if let Some(error) = response.error {
+            return Err(ExecError::Plugin(info.command.clone(), error));
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The reference plugin in tests/plugins is built as an example by `cargo test`
+    fn plugin() -> String {
+        let exe = std::env::current_exe().unwrap();
+        let dir = exe.parent().unwrap().parent().unwrap();
+        let plugin = dir.join("examples").join("exec-plugin");
+        assert!(
+            plugin.exists(),
+            "build the plugin with `cargo build --examples`"
+        );
+        plugin.display().to_string()
+    }
+
+    #[tokio::test]
+    async fn reference_plugin_round_trip() {
+        let dir = std::env::temp_dir().join(format!("secrets-manager-exec-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = dir.join("store.json").display().to_string();
+        let key_ref: KeyRef = "dev.PASS".parse().unwrap();
+        let token: KeyRef = "TOKEN".parse().unwrap();
+        let dev_token: KeyRef = "dev.TOKEN".parse().unwrap();
+
+        for set in [true, false] {
+            let mut args = vec![store.clone()];
+            if !set {
+                args.push(String::from("--no-set"));
+            }
+            let info = ExecVault::info(plugin(), args);
+            let mut vault = ExecVault::from_info(&info).await.unwrap();
+            assert_eq!(vault.supports("set"), set);
+            vault.get_mut().set(key_ref.clone(), format!("hunter{set}"));
+            vault.get_mut().set(token.clone(), "x");
+            vault.save().await.unwrap();
+            // same value as the parent TOKEN, still a key of its own
+            vault.get_mut().set(dev_token.clone(), "x");
+            vault.save().await.unwrap();
+            let mut vault = ExecVault::from_info(&info).await.unwrap();
+            assert_eq!(vault.get().get_exact(&dev_token).unwrap(), "x");
+            vault.get_mut().remove(&token);
+            vault.get_mut().remove(&dev_token);
+            vault.save().await.unwrap();
+
+            let vault = ExecVault::from_info(&info).await.unwrap();
+            assert_eq!(vault.get().keys("/").count(), 1);
+            assert_eq!(vault.get().get(&key_ref).unwrap(), &format!("hunter{set}"));
+            // the plugin answers `get`, advertised in the descriptor saved in the config
+            let kind = vault.to_vault_kind();
+            assert!(kind.can_fetch());
+            let fetched = kind.fetch(&key_ref).await.unwrap();
+            assert_eq!(fetched, Some(format!("hunter{set}")));
+            assert_eq!(kind.fetch(&token).await.unwrap(), None);
+        }
+        // descriptors written before the plugin was loaded do not know about `get`
+        assert!(!VaultKind::Exec(ExecVault::info(plugin(), vec![store.clone()])).can_fetch());
+
+        let info = ExecVault::info(plugin(), vec![store, String::from("--version=2")]);
+        assert!(matches!(
+            ExecVault::from_info(&info).await,
+            Err(ExecError::UnsupportedVersion(_, 2))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,471 @@
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path, PathBuf},
+};
+
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::{Configuration, KeyRef};
+
+use super::{typed, VaultError, VaultKind, VaultTrait};
+
+#[derive(thiserror::Error, Debug)]
+pub enum HashicorpError {
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error("Vault returned {0} for {1}: {2}")]
+    Status(StatusCode, String, String),
+    #[error("No Vault token found, set VAULT_TOKEN or log in with `vault login`")]
+    MissingToken,
+    #[error("Set VAULT_SECRET_ID to log in with AppRole {0}")]
+    MissingSecretId(String),
+}
+
+fn default_mount() -> String {
+    String::from("secret")
+}
+
+fn default_approle_mount() -> String {
+    String::from("approle")
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "method")]
+pub enum HashicorpAuth {
+    /// Token read from VAULT_TOKEN or ~/.vault-token
+    Token,
+    /// AppRole login, the secret id is read from VAULT_SECRET_ID
+    AppRole {
+        role_id: String,
+        #[serde(default = "default_approle_mount")]
+        mount: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HashicorpVaultInfo {
+    address: String,
+    /// Mount point of the KV v2 engine
+    #[serde(default = "default_mount")]
+    mount: String,
+    /// Secret path, inside the mount, under which the configuration is stored
+    path: String,
+    #[serde(default)]
+    namespace: Option<String>,
+    auth: HashicorpAuth,
+}
+
+/// Stores each path of the configuration as a KV v2 secret under `<mount>/<path>`,
+/// keys stored at the root of the configuration are kept in `<mount>/<path>` itself
+pub struct HashicorpVault {
+    client: Client,
+    token: String,
+    info: HashicorpVaultInfo,
+    secret_value: Configuration<String>,
+    /// Value as loaded from the server, only paths that changed are written back
+    base_value: Configuration<String>,
+    /// Secrets as stored on the server, values that are not strings keep their type
+    raw: BTreeMap<PathBuf, Map<String, Value>>,
+}
+
+#[async_trait::async_trait]
+impl VaultTrait for HashicorpVault {
+    fn get(&self) -> &Configuration<String> {
+        &self.secret_value
+    }
+
+    fn get_mut(&mut self) -> &mut Configuration<String> {
+        &mut self.secret_value
+    }
+
+    fn to_vault_kind(&self) -> VaultKind {
+        VaultKind::HashicorpVault(self.info.clone())
+    }
+
+    async fn save(&mut self) -> Result<(), VaultError> {
+        self.save_secrets().await?;
+        Ok(())
+    }
+}
+
+impl HashicorpVault {
+    pub fn info(
+        address: String,
+        mount: String,
+        path: String,
+        namespace: Option<String>,
+        approle: Option<(String, String)>,
+    ) -> HashicorpVaultInfo {
+        let auth = match approle {
+            Some((role_id, mount)) => HashicorpAuth::AppRole { role_id, mount },
+            None => HashicorpAuth::Token,
+        };
+        HashicorpVaultInfo {
+            address: address.trim_end_matches('/').to_string(),
+            mount: mount.trim_matches('/').to_string(),
+            path: path.trim_matches('/').to_string(),
+            namespace,
+            auth,
+        }
+    }
+
+    pub async fn from_info(info: &HashicorpVaultInfo) -> Result<Self, HashicorpError> {
+        let client = Client::new();
+        let token = Self::login(&client, info).await?;
+        Self::with_token(client, info, token).await
+    }
+
+    async fn with_token(
+        client: Client,
+        info: &HashicorpVaultInfo,
+        token: String,
+    ) -> Result<Self, HashicorpError> {
+        let mut vault = Self {
+            client,
+            token,
+            info: info.clone(),
+            secret_value: Configuration::new(),
+            base_value: Configuration::new(),
+            raw: BTreeMap::new(),
+        };
+        let mut pending = vec![PathBuf::from("/")];
+        while let Some(path) = pending.pop() {
+            if let Some(values) = vault.read_secret(&path).await? {
+                for (key, value) in &values {
+                    let key_ref = KeyRef {
+                        path: path.clone(),
+                        key: key.clone(),
+                    };
+                    vault.secret_value.set(key_ref, as_text(value));
+                }
+                vault.raw.insert(path.clone(), values);
+            }
+            for child in vault.list(&path).await? {
+                pending.push(path.join(child));
+            }
+        }
+        vault.base_value = vault.secret_value.clone();
+        Ok(vault)
+    }
+
+    async fn login(client: &Client, info: &HashicorpVaultInfo) -> Result<String, HashicorpError> {
+        match info.auth {
+            HashicorpAuth::Token => {
+                if let Ok(token) = std::env::var("VAULT_TOKEN") {
+                    return Ok(token);
+                }
+                let home = std::env::var_os("HOME").ok_or(HashicorpError::MissingToken)?;
+                std::fs::read_to_string(Path::new(&home).join(".vault-token"))
+                    .map(|t| t.trim().to_string())
+                    .map_err(|_| HashicorpError::MissingToken)
+            }
+            HashicorpAuth::AppRole {
+                ref role_id,
+                ref mount,
+            } => {
+                let secret_id = std::env::var("VAULT_SECRET_ID")
+                    .map_err(|_| HashicorpError::MissingSecretId(role_id.clone()))?;
+                let url = format!("{}/v1/auth/{}/login", info.address, mount);
+                let mut request = client
+                    .post(&url)
+                    .json(&json!({ "role_id": role_id, "secret_id": secret_id }));
+                if let Some(ref namespace) = info.namespace {
+                    request = request.header("X-Vault-Namespace", namespace);
+                }
+                let response = Self::send(request, &url).await?.unwrap_or_default();
+                let token = response["auth"]["client_token"].as_str().ok_or_else(|| {
+                    HashicorpError::Status(
+                        StatusCode::OK,
+                        url,
+                        String::from("missing client token in login response"),
+                    )
+                })?;
+                Ok(token.to_string())
+            }
+        }
+    }
+
+    /// Path of the secret, relative to the mount, that stores `path`
+    fn secret_path(&self, path: &Path) -> String {
+        let mut parts = vec![self.info.path.as_str()];
+        parts.extend(path.components().filter_map(|c| match c {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        }));
+        parts.retain(|p| !p.is_empty());
+        parts.join("/")
+    }
+
+    fn request(&self, method: reqwest::Method, url: &str) -> RequestBuilder {
+        let request = self
+            .client
+            .request(method, url)
+            .header("X-Vault-Token", &self.token);
+        match self.info.namespace {
+            Some(ref namespace) => request.header("X-Vault-Namespace", namespace),
+            None => request,
+        }
+    }
+
+    /// Sends the request, a 404 is returned as `None`
+    async fn send(request: RequestBuilder, url: &str) -> Result<Option<Value>, HashicorpError> {
+        let response = request.send().await?;
+        let status = response.status();
+        if status == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(HashicorpError::Status(status, url.to_string(), body));
+        }
+        if status == StatusCode::NO_CONTENT {
+            return Ok(Some(Value::Null));
+        }
+        Ok(Some(response.json().await?))
+    }
+
+    async fn read_secret(&self, path: &Path) -> Result<Option<Map<String, Value>>, HashicorpError> {
+        let url = format!(
+            "{}/v1/{}/data/{}",
+            self.info.address,
+            self.info.mount,
+            self.secret_path(path)
+        );
+        let Some(mut response) = Self::send(self.request(reqwest::Method::GET, &url), &url).await?
+        else {
+            return Ok(None);
+        };
+        match response["data"].get_mut("data").map(Value::take) {
+            Some(Value::Object(data)) => Ok(Some(data)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Names of the folders under `path`, secrets are read from the same path as their folder
+    async fn list(&self, path: &Path) -> Result<Vec<String>, HashicorpError> {
+        let url = format!(
+            "{}/v1/{}/metadata/{}?list=true",
+            self.info.address,
+            self.info.mount,
+            self.secret_path(path)
+        );
+        let Some(response) = Self::send(self.request(reqwest::Method::GET, &url), &url).await?
+        else {
+            return Ok(Vec::new());
+        };
+        let mut children: Vec<String> = response["data"]["keys"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|key| key.as_str())
+            .map(|key| key.trim_end_matches('/').to_string())
+            .collect();
+        children.sort();
+        children.dedup();
+        Ok(children)
+    }
+
+    async fn save_secrets(&mut self) -> Result<(), HashicorpError> {
+        let current: BTreeMap<_, _> = self.secret_value.sections().collect();
+        let base: BTreeMap<_, _> = self.base_value.sections().collect();
+        for (path, values) in current.iter().filter(|(_, v)| !v.is_empty()) {
+            if base.get(path) == Some(values) {
+                continue;
+            }
+            // values are written back as they were stored, edited ones keep their type
+            let stored = self.raw.get(*path);
+            let data: Map<String, Value> = values
+                .iter()
+                .map(|(key, value)| {
+                    let previous = stored.and_then(|s| s.get(key));
+                    let value = match previous {
+                        Some(previous) if as_text(previous) == *value => previous.clone(),
+                        previous => typed(previous, value),
+                    };
+                    (key.clone(), value)
+                })
+                .collect();
+            let url = format!(
+                "{}/v1/{}/data/{}",
+                self.info.address,
+                self.info.mount,
+                self.secret_path(path)
+            );
+            let request = self
+                .request(reqwest::Method::POST, &url)
+                .json(&json!({ "data": data }));
+            Self::send(request, &url).await?;
+            self.raw.insert(path.to_path_buf(), data);
+        }
+        for path in base.keys() {
+            if current.get(path).is_some_and(|v| !v.is_empty()) {
+                continue;
+            }
+            // soft delete, previous versions can still be recovered
+            let url = format!(
+                "{}/v1/{}/data/{}",
+                self.info.address,
+                self.info.mount,
+                self.secret_path(path)
+            );
+            Self::send(self.request(reqwest::Method::DELETE, &url), &url).await?;
+            self.raw.remove(*path);
+        }
+        self.base_value = self.secret_value.clone();
+        Ok(())
+    }
+}
+
+/// Values that are not strings, e.g numbers or nested objects, are shown as JSON
+fn as_text(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    type Store = Arc<Mutex<BTreeMap<String, Value>>>;
+
+    /// Minimal stand-in for the KV v2 HTTP API mounted at `secret`, one request per connection
+    async fn serve(store: Store) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = String::new();
+                let mut buf = [0; 4096];
+                let (head, body) = loop {
+                    let read = socket.read(&mut buf).await.unwrap();
+                    assert_ne!(read, 0, "connection closed before the end of the request");
+                    request.push_str(std::str::from_utf8(&buf[..read]).unwrap());
+                    let Some((head, body)) = request.split_once("\r\n\r\n") else {
+                        continue;
+                    };
+                    let length = head
+                        .to_ascii_lowercase()
+                        .lines()
+                        .find_map(|l| l.strip_prefix("content-length:")?.trim().parse().ok());
+                    if body.len() >= length.unwrap_or(0) {
+                        break (head, body);
+                    }
+                };
+                let mut line = head.split_whitespace();
+                let (method, target) = (line.next().unwrap(), line.next().unwrap());
+                let (status, response) = handle(&store, method, target, body.as_bytes());
+                let response = format!(
+                    "HTTP/1.1 {status}\r\nconnection: close\r\ncontent-length: {}\r\n\r\n{response}",
+                    response.len()
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        address
+    }
+
+    fn handle(store: &Store, method: &str, target: &str, body: &[u8]) -> (&'static str, String) {
+        let mut store = store.lock().unwrap();
+        if let Some(path) = target.strip_prefix("/v1/secret/metadata/") {
+            let prefix = format!("{}/", path.trim_end_matches("?list=true"));
+            let mut keys: Vec<String> = store
+                .keys()
+                .filter_map(|k| k.strip_prefix(&prefix))
+                .map(|k| match k.split_once('/') {
+                    Some((folder, _)) => format!("{}/", folder),
+                    None => k.to_string(),
+                })
+                .collect();
+            keys.dedup();
+            if keys.is_empty() {
+                return ("404 Not Found", String::from("{\"errors\":[]}"));
+            }
+            return ("200 OK", json!({ "data": { "keys": keys } }).to_string());
+        }
+        let path = target.strip_prefix("/v1/secret/data/").unwrap().to_string();
+        match method {
+            "GET" => match store.get(&path) {
+                Some(data) => ("200 OK", json!({ "data": { "data": data } }).to_string()),
+                None => ("404 Not Found", String::from("{\"errors\":[]}")),
+            },
+            "POST" => {
+                let body: Value = serde_json::from_slice(body).unwrap();
+                store.insert(path, body["data"].clone());
+                ("200 OK", String::from("{}"))
+            }
+            "DELETE" => {
+                store.remove(&path);
+                ("204 No Content", String::new())
+            }
+            _ => ("405 Method Not Allowed", String::new()),
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip_paths() {
+        let store = Store::default();
+        store.lock().unwrap().insert(
+            String::from("team/app/dev"),
+            json!({ "DB_PASS": "hunter2", "PORT": 5432, "DEBUG": false, "TLS": { "ca": "x" } }),
+        );
+        store
+            .lock()
+            .unwrap()
+            .insert(String::from("team/app"), json!({ "TOKEN": "abc" }));
+        let address = serve(store.clone()).await;
+        let info = HashicorpVault::info(
+            address,
+            default_mount(),
+            String::from("/team/app/"),
+            None,
+            None,
+        );
+
+        let token = String::from("test-token");
+        let mut vault = HashicorpVault::with_token(Client::new(), &info, token)
+            .await
+            .unwrap();
+        let get =
+            |vault: &HashicorpVault, key: &str| vault.get().get(&key.parse().unwrap()).cloned();
+        assert_eq!(get(&vault, "dev.DB_PASS").unwrap(), "hunter2");
+        assert_eq!(get(&vault, "dev.PORT").unwrap(), "5432");
+        assert_eq!(get(&vault, "dev.TLS").unwrap(), r#"{"ca":"x"}"#);
+        assert_eq!(get(&vault, "TOKEN").unwrap(), "abc");
+
+        vault
+            .get_mut()
+            .set("prod.db.DB_PASS".parse().unwrap(), "secret");
+        vault.get_mut().remove(&"TOKEN".parse().unwrap());
+        // edited values keep their type, the others are written back as they were
+        vault.get_mut().set("dev.PORT".parse().unwrap(), "5433");
+        vault.get_mut().set("dev.DEBUG".parse().unwrap(), "verbose");
+        vault.save().await.unwrap();
+
+        let store = store.lock().unwrap();
+        assert_eq!(
+            store.get("team/app/prod/db"),
+            Some(&json!({ "DB_PASS": "secret" }))
+        );
+        assert_eq!(store.get("team/app"), None);
+        assert_eq!(
+            store.get("team/app/dev"),
+            Some(
+                &json!({ "DB_PASS": "hunter2", "PORT": 5433, "DEBUG": "verbose", "TLS": { "ca": "x" } })
+            )
+        );
+    }
+}
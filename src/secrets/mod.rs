@@ -1,24 +1,91 @@
+mod age_file;
 mod aws;
+mod cache;
+mod exec;
+mod hashicorp;
+mod password_store;
+mod secure_store;
+mod sops;
+mod ssm;
+
+use std::{
+    path::{Component, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
 
 use serde::{Deserialize, Serialize};
 
+use age_file::AgeFileInfo;
+pub use age_file::AgeFileVault;
 use aws::AwsSecretInfo;
-pub use aws::AwsSecretVault;
+pub use aws::{AwsLayout, AwsSecretVault};
+pub use cache::{CacheSettings, VaultCache};
+use exec::ExecInfo;
+pub use exec::ExecVault;
+pub use hashicorp::HashicorpVault;
+use hashicorp::HashicorpVaultInfo;
+use password_store::PasswordStoreInfo;
+pub use password_store::PasswordStoreVault;
+use secure_store::SecureStoreInfo;
+pub use secure_store::SecureStoreVault;
+use sops::SopsInfo;
+pub use sops::SopsVault;
+use ssm::AwsSsmInfo;
+pub use ssm::AwsSsmVault;
 
-use crate::Configuration;
+use crate::{Configuration, KeyRef};
 
+use self::age_file::AgeError;
 use self::aws::AwsError;
+use self::cache::CacheError;
+use self::exec::ExecError;
+use self::hashicorp::HashicorpError;
+use self::password_store::PasswordStoreError;
+use self::secure_store::SecureStoreError;
+use self::sops::SopsError;
+use self::ssm::SsmError;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "provider")]
 pub enum VaultKind {
     AwsSecretManager(AwsSecretInfo),
+    SecureStore(SecureStoreInfo),
+    HashicorpVault(HashicorpVaultInfo),
+    AwsSsm(AwsSsmInfo),
+    Exec(ExecInfo),
+    PasswordStore(PasswordStoreInfo),
+    AgeFile(AgeFileInfo),
+    Sops(SopsInfo),
 }
 
 impl VaultKind {
     pub async fn into_vault(self) -> Result<Box<dyn VaultTrait>, VaultError> {
         match self {
             Self::AwsSecretManager(info) => Ok(Box::new(AwsSecretVault::from_info(&info).await?)),
+            Self::SecureStore(info) => Ok(Box::new(SecureStoreVault::from_info(&info)?)),
+            Self::HashicorpVault(info) => Ok(Box::new(HashicorpVault::from_info(&info).await?)),
+            Self::AwsSsm(info) => Ok(Box::new(AwsSsmVault::from_info(&info).await?)),
+            Self::Exec(info) => Ok(Box::new(ExecVault::from_info(&info).await?)),
+            Self::PasswordStore(info) => Ok(Box::new(PasswordStoreVault::from_info(&info).await?)),
+            Self::AgeFile(info) => Ok(Box::new(AgeFileVault::from_info(&info)?)),
+            Self::Sops(info) => Ok(Box::new(SopsVault::from_info(&info).await?)),
+        }
+    }
+
+    /// Whether single keys are read by [`VaultKind::fetch`] without loading the whole vault
+    pub fn can_fetch(&self) -> bool {
+        matches!(self, Self::Exec(info) if info.supports("get"))
+    }
+
+    /// Reads the single key `key_ref`, vaults that cannot, see [`VaultKind::can_fetch`],
+    /// are loaded to read it
+    pub async fn fetch(&self, key_ref: &KeyRef) -> Result<Option<String>, VaultError> {
+        match self {
+            Self::Exec(info) if info.supports("get") => Ok(ExecVault::fetch(info, key_ref).await?),
+            _ => Ok(self.clone().into_vault().await?.get().get(key_ref).cloned()),
         }
     }
 }
@@ -27,12 +94,217 @@ impl VaultKind {
 pub enum VaultError {
     #[error(transparent)]
     Aws(#[from] AwsError),
+    #[error(transparent)]
+    SecureStore(#[from] SecureStoreError),
+    #[error(transparent)]
+    Hashicorp(#[from] HashicorpError),
+    #[error(transparent)]
+    Ssm(#[from] SsmError),
+    #[error(transparent)]
+    Exec(#[from] ExecError),
+    #[error(transparent)]
+    PasswordStore(#[from] PasswordStoreError),
+    #[error(transparent)]
+    Age(#[from] AgeError),
+    #[error(transparent)]
+    Sops(#[from] SopsError),
+    #[error(transparent)]
+    Cache(#[from] CacheError),
+    #[error("Vault {0} is not cached, run `secrets-manager secret refresh` while online")]
+    NotCached(String),
+    #[error("Vault {0} cannot be modified while offline")]
+    Offline(String),
+    #[error("Vault {0} was changed by someone else since it was loaded, conflicting keys: {}", .1.join(", "))]
+    Conflict(String, Vec<String>),
 }
 
 #[async_trait::async_trait]
-pub trait VaultTrait {
+pub trait VaultTrait: Send + Sync {
     fn get(&self) -> &Configuration<String>;
     fn get_mut(&mut self) -> &mut Configuration<String>;
     async fn save(&mut self) -> Result<(), VaultError>;
-    fn into_vault_kind(&self) -> VaultKind;
+    fn to_vault_kind(&self) -> VaultKind;
+    /// Version id of the loaded content, for backends that keep versions
+    fn version(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Vault descriptor that is only turned into a live vault the first time it is needed
+pub struct LazyVault {
+    kind: VaultKind,
+    vault: OnceLock<Box<dyn VaultTrait>>,
+    /// Whether `vault` is a copy served from the cache
+    cached: AtomicBool,
+}
+
+impl LazyVault {
+    pub fn new(kind: VaultKind) -> Self {
+        Self {
+            kind,
+            vault: OnceLock::new(),
+            cached: AtomicBool::new(false),
+        }
+    }
+
+    pub fn loaded(vault: Box<dyn VaultTrait>) -> Self {
+        Self {
+            kind: vault.to_vault_kind(),
+            vault: OnceLock::from(vault),
+            cached: AtomicBool::new(false),
+        }
+    }
+
+    pub fn kind(&self) -> &VaultKind {
+        &self.kind
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.vault.get().is_some()
+    }
+
+    /// Stores the vault built from this descriptor, a vault that is already loaded is kept
+    pub fn set_loaded(&self, vault: Box<dyn VaultTrait>) {
+        let _ = self.vault.set(vault);
+    }
+
+    /// Like [`LazyVault::set_loaded`], for a copy read from the cache
+    pub fn set_cached(&self, vault: Box<dyn VaultTrait>) {
+        if self.vault.set(vault).is_ok() {
+            self.cached.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_cached(&self) -> bool {
+        self.cached.load(Ordering::Relaxed)
+    }
+
+    /// Replaces the loaded vault, e.g a cached copy with the live vault
+    pub fn replace(&mut self, vault: Box<dyn VaultTrait>) {
+        self.vault = OnceLock::from(vault);
+        *self.cached.get_mut() = false;
+    }
+
+    pub fn get(&self) -> Option<&dyn VaultTrait> {
+        self.vault.get().map(|v| v.as_ref())
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut Box<dyn VaultTrait>> {
+        self.vault.get_mut()
+    }
+
+    pub fn to_vault_kind(&self) -> VaultKind {
+        self.get()
+            .map(|v| v.to_vault_kind())
+            .unwrap_or_else(|| self.kind.clone())
+    }
+}
+
+/// `value` with the type of the JSON value it replaces, if it still parses as one
+fn typed(previous: Option<&serde_json::Value>, value: &str) -> serde_json::Value {
+    use serde_json::Value;
+    let parsed = match previous {
+        Some(Value::Bool(_)) => value.parse().ok().map(Value::Bool),
+        Some(Value::Number(_)) => value.parse().ok().map(Value::Number),
+        _ => None,
+    };
+    parsed.unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+/// Name of the entry storing `key_ref` in a flat store, `<prefix>/<path>/<key>`
+fn key_name(prefix: &str, key_ref: &KeyRef) -> String {
+    let mut name = String::from(prefix);
+    for component in key_ref.path.components() {
+        if let Component::Normal(part) = component {
+            name.push('/');
+            name.push_str(&part.to_string_lossy());
+        }
+    }
+    name.push('/');
+    name.push_str(&key_ref.key);
+    name
+}
+
+/// Inverse of [`key_name`], `None` if `name` is not under `prefix`
+fn key_from_name(prefix: &str, name: &str) -> Option<KeyRef> {
+    let rest = name.strip_prefix(prefix)?.strip_prefix('/')?;
+    let (path, key) = rest.rsplit_once('/').unwrap_or(("", rest));
+    Some(KeyRef {
+        path: PathBuf::from("/").join(path),
+        key: key.to_string(),
+    })
+}
+
+/// Stand-in for AWS services speaking the JSON protocol, for the tests of the AWS backends
+#[cfg(test)]
+mod fake_aws {
+    use std::{fmt, sync::Arc};
+
+    use aws_smithy_runtime_api::client::{
+        http::{
+            http_client_fn, HttpConnector, HttpConnectorFuture, SharedHttpClient,
+            SharedHttpConnector,
+        },
+        orchestrator::{HttpRequest, HttpResponse},
+    };
+    use serde_json::{json, Value};
+
+    type Handler = dyn Fn(&str, &Value) -> Result<Value, &'static str> + Send + Sync;
+
+    /// Answers each request with `handler`, called with the operation name and the request body.
+    /// Errors are the name of the AWS exception to return
+    #[derive(Clone)]
+    pub struct FakeAws(Arc<Handler>);
+
+    impl FakeAws {
+        pub fn new(
+            handler: impl Fn(&str, &Value) -> Result<Value, &'static str> + Send + Sync + 'static,
+        ) -> Self {
+            Self(Arc::new(handler))
+        }
+
+        pub fn http_client(self) -> SharedHttpClient {
+            http_client_fn(move |_, _| SharedHttpConnector::new(self.clone()))
+        }
+    }
+
+    impl fmt::Debug for FakeAws {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("FakeAws")
+        }
+    }
+
+    impl HttpConnector for FakeAws {
+        fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+            // e.g secretsmanager.GetSecretValue or AmazonSSM.PutParameter
+            let target = request.headers().get("x-amz-target").unwrap_or_default();
+            let operation = target.rsplit('.').next().unwrap_or_default();
+            let body: Value = serde_json::from_slice(request.body().bytes().unwrap()).unwrap();
+            let (status, body) = match (self.0)(operation, &body) {
+                Ok(body) => (200, body),
+                Err(error) => (400, json!({ "__type": error, "message": error })),
+            };
+            let response = HttpResponse::new(status.try_into().unwrap(), body.to_string().into());
+            HttpConnectorFuture::ready(Ok(response))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_names_round_trip() {
+        let key_ref: KeyRef = "dev.ios.DB_PASS".parse().unwrap();
+        let name = key_name("/app", &key_ref);
+        assert_eq!(name, "/app/dev/ios/DB_PASS");
+        assert_eq!(key_from_name("/app", &name), Some(key_ref));
+
+        let root: KeyRef = "TOKEN".parse().unwrap();
+        assert_eq!(key_name("/app", &root), "/app/TOKEN");
+        assert_eq!(key_from_name("/app", "/app/TOKEN"), Some(root));
+        assert_eq!(key_from_name("/app", "/other/TOKEN"), None);
+        assert_eq!(key_from_name("/app", "/application/TOKEN"), None);
+    }
 }
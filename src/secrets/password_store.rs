@@ -0,0 +1,315 @@
+use std::{
+    path::{Component, Path, PathBuf},
+    process::Stdio,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::{Configuration, KeyRef};
+
+use super::{VaultError, VaultKind, VaultTrait};
+
+#[derive(thiserror::Error, Debug)]
+pub enum PasswordStoreError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("gpg failed on {0}: {1}")]
+    Gpg(String, String),
+    #[error("No .gpg-id found for {0}, initialize the store with `pass init`")]
+    MissingGpgId(String),
+    #[error("Password store not found, set PASSWORD_STORE_DIR or HOME")]
+    MissingStore,
+    #[error("Invalid entry name {0}")]
+    InvalidName(String),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PasswordStoreInfo {
+    /// Root of the store, PASSWORD_STORE_DIR or ~/.password-store when not set
+    #[serde(default)]
+    dir: Option<PathBuf>,
+    /// Folder of the store holding the entries of this vault
+    #[serde(default)]
+    prefix: PathBuf,
+}
+
+/// Reads and writes the entries of a `pass` store by shelling out to `gpg`,
+/// the key `path.to.KEY` is the entry `<prefix>/path/to/KEY.gpg`.
+/// Entries are encrypted for the ids in the nearest `.gpg-id`, like `pass` does.
+///
+/// Dots separate the parts of a key, entries or folders with a dot in their name,
+/// e.g `github.com.gpg`, are loaded but cannot be referenced
+pub struct PasswordStoreVault {
+    info: PasswordStoreInfo,
+    root: PathBuf,
+    /// GNUPGHOME passed to gpg, the inherited one when not set
+    gnupg_home: Option<PathBuf>,
+    secret_value: Configuration<String>,
+    /// Value as read from the store, only entries that changed are encrypted again
+    base_value: Configuration<String>,
+}
+
+#[async_trait::async_trait]
+impl VaultTrait for PasswordStoreVault {
+    fn get(&self) -> &Configuration<String> {
+        &self.secret_value
+    }
+
+    fn get_mut(&mut self) -> &mut Configuration<String> {
+        &mut self.secret_value
+    }
+
+    fn to_vault_kind(&self) -> VaultKind {
+        VaultKind::PasswordStore(self.info.clone())
+    }
+
+    async fn save(&mut self) -> Result<(), VaultError> {
+        self.save_entries().await?;
+        Ok(())
+    }
+}
+
+impl PasswordStoreVault {
+    pub fn info(
+        dir: Option<PathBuf>,
+        prefix: PathBuf,
+    ) -> Result<PasswordStoreInfo, std::io::Error> {
+        Ok(PasswordStoreInfo {
+            dir: dir.map(std::path::absolute).transpose()?,
+            prefix,
+        })
+    }
+
+    pub async fn from_info(info: &PasswordStoreInfo) -> Result<Self, PasswordStoreError> {
+        Self::load(info, None).await
+    }
+
+    async fn load(
+        info: &PasswordStoreInfo,
+        gnupg_home: Option<PathBuf>,
+    ) -> Result<Self, PasswordStoreError> {
+        let root = match info.dir {
+            Some(ref dir) => dir.clone(),
+            None => match std::env::var_os("PASSWORD_STORE_DIR") {
+                Some(dir) => PathBuf::from(dir),
+                None => {
+                    PathBuf::from(std::env::var_os("HOME").ok_or(PasswordStoreError::MissingStore)?)
+                        .join(".password-store")
+                }
+            },
+        };
+        let mut vault = Self {
+            info: info.clone(),
+            root,
+            gnupg_home,
+            secret_value: Configuration::new(),
+            base_value: Configuration::new(),
+        };
+        let mut pending = vec![PathBuf::from("/")];
+        while let Some(path) = pending.pop() {
+            let dir = vault.entry_dir(&path);
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for entry in entries {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                if name.starts_with('.') {
+                    continue;
+                }
+                if entry.file_type()?.is_dir() {
+                    pending.push(path.join(name));
+                } else if let Some(key) = name.strip_suffix(".gpg") {
+                    let value = decrypt(vault.gpg(), &entry.path()).await?;
+                    vault.secret_value.set(
+                        KeyRef {
+                            path: path.clone(),
+                            key: key.to_string(),
+                        },
+                        value,
+                    );
+                }
+            }
+        }
+        vault.base_value = vault.secret_value.clone();
+        Ok(vault)
+    }
+
+    fn gpg(&self) -> Command {
+        let mut command = Command::new("gpg");
+        if let Some(ref home) = self.gnupg_home {
+            command.env("GNUPGHOME", home);
+        }
+        command
+    }
+
+    fn entry_dir(&self, path: &Path) -> PathBuf {
+        let mut dir = self.root.join(&self.info.prefix);
+        dir.extend(path.components().filter_map(|c| match c {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        }));
+        dir
+    }
+
+    fn entry_path(&self, key_ref: &KeyRef) -> Result<PathBuf, PasswordStoreError> {
+        if key_ref.key.is_empty() || key_ref.key.contains(['/', '\\']) || key_ref.key == ".." {
+            return Err(PasswordStoreError::InvalidName(key_ref.key.clone()));
+        }
+        Ok(self
+            .entry_dir(&key_ref.path)
+            .join(format!("{}.gpg", key_ref.key)))
+    }
+
+    /// Ids from the `.gpg-id` nearest to `dir`, without leaving the store
+    fn recipients(&self, dir: &Path) -> Result<Vec<String>, PasswordStoreError> {
+        for dir in dir.ancestors().take_while(|d| d.starts_with(&self.root)) {
+            if let Ok(ids) = std::fs::read_to_string(dir.join(".gpg-id")) {
+                return Ok(ids
+                    .lines()
+                    .map(|l| l.trim())
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .map(|l| l.to_string())
+                    .collect());
+            }
+        }
+        Err(PasswordStoreError::MissingGpgId(dir.display().to_string()))
+    }
+
+    async fn save_entries(&mut self) -> Result<(), PasswordStoreError> {
+        // keys are compared as stored, a value inherited from a parent path is an entry of its own
+        for key_ref in self.secret_value.changed_keys(&self.base_value) {
+            let path = self.entry_path(&key_ref)?;
+            let Some(value) = self.secret_value.get_exact(&key_ref) else {
+                match std::fs::remove_file(path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => continue,
+                }
+            };
+            let dir = path.parent().unwrap_or(&self.root);
+            std::fs::create_dir_all(dir)?;
+            let recipients = self.recipients(dir)?;
+            encrypt(self.gpg(), &path, &recipients, value).await?;
+        }
+        self.base_value = self.secret_value.clone();
+        Ok(())
+    }
+}
+
+/// Content of the entry, without the trailing new line added by `pass insert`
+async fn decrypt(mut gpg: Command, path: &Path) -> Result<String, PasswordStoreError> {
+    let output = gpg
+        .args(["--batch", "--quiet", "--yes", "--decrypt"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(PasswordStoreError::Gpg(
+            path.display().to_string(),
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    let mut value = String::from_utf8_lossy(&output.stdout).to_string();
+    if value.ends_with('\n') {
+        value.pop();
+    }
+    Ok(value)
+}
+
+async fn encrypt(
+    mut gpg: Command,
+    path: &Path,
+    recipients: &[String],
+    value: &str,
+) -> Result<(), PasswordStoreError> {
+    gpg.args(["--batch", "--quiet", "--yes", "--encrypt"]);
+    for recipient in recipients {
+        gpg.arg("--recipient").arg(recipient);
+    }
+    let mut child = gpg
+        .arg("--output")
+        .arg(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(value.as_bytes()).await?;
+        stdin.write_all(b"\n").await?;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(PasswordStoreError::Gpg(
+            path.display().to_string(),
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes and reads entries with a key generated in a throwaway GnuPG home
+    #[tokio::test]
+    #[ignore = "needs the gpg binary"]
+    async fn round_trip_with_throwaway_gpg_home() {
+        let dir = std::env::temp_dir().join(format!("secrets-manager-pass-{}", std::process::id()));
+        let gnupg = dir.join("gnupg");
+        let store = dir.join("store");
+        std::fs::create_dir_all(&store).unwrap();
+        std::fs::create_dir_all(&gnupg).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&gnupg, std::fs::Permissions::from_mode(0o700)).unwrap();
+        }
+        let generated = Command::new("gpg")
+            .env("GNUPGHOME", &gnupg)
+            .args(["--batch", "--passphrase", "", "--quick-gen-key"])
+            .args([
+                "test@secrets-manager.invalid",
+                "default",
+                "default",
+                "never",
+            ])
+            .output()
+            .await
+            .expect("gpg is installed");
+        assert!(generated.status.success(), "{:?}", generated);
+        std::fs::write(store.join(".gpg-id"), "test@secrets-manager.invalid\n").unwrap();
+
+        let info = PasswordStoreVault::info(Some(store.clone()), PathBuf::from("work")).unwrap();
+        let load = || PasswordStoreVault::load(&info, Some(gnupg.clone()));
+        let mut vault = load().await.unwrap();
+        let key_ref: KeyRef = "dev.PASS".parse().unwrap();
+        let token: KeyRef = "TOKEN".parse().unwrap();
+        let dev_token: KeyRef = "dev.TOKEN".parse().unwrap();
+        vault.get_mut().set(key_ref.clone(), "hunter2\nuser: joe");
+        vault.get_mut().set(token.clone(), "x");
+        vault.save().await.unwrap();
+        assert!(store.join("work/dev/PASS.gpg").exists());
+        // same value as the parent TOKEN, still an entry of its own
+        vault.get_mut().set(dev_token.clone(), "x");
+        vault.save().await.unwrap();
+        assert!(store.join("work/dev/TOKEN.gpg").exists());
+        vault.get_mut().remove(&token);
+        vault.get_mut().remove(&dev_token);
+        vault.save().await.unwrap();
+        assert!(!store.join("work/TOKEN.gpg").exists());
+        assert!(!store.join("work/dev/TOKEN.gpg").exists());
+
+        let vault = load().await.unwrap();
+        assert_eq!(
+            vault.get().get(&key_ref),
+            Some(&String::from("hunter2\nuser: joe"))
+        );
+        assert_eq!(vault.get().keys("/").count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
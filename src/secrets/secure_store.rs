@@ -0,0 +1,162 @@
+use std::path::{Path, PathBuf};
+
+use securestore::{KeySource, SecretsManager};
+use serde::{Deserialize, Serialize};
+
+use crate::{Configuration, KeyRef};
+
+use super::{VaultError, VaultKind, VaultTrait};
+
+/// Environment variable read to unlock stores that are not backed by a key file
+pub const PASSWORD_ENV: &str = "SECRETS_MANAGER_SECURESTORE_PASSWORD";
+
+#[derive(thiserror::Error, Debug)]
+pub enum SecureStoreError {
+    #[error(transparent)]
+    Store(#[from] securestore::Error),
+    #[error("No key file configured for {0}, set {PASSWORD_ENV} to unlock it with a password")]
+    MissingPassword(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Invalid secret name {0} in store")]
+    InvalidKey(String),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SecureStoreInfo {
+    path: PathBuf,
+    key_file: Option<PathBuf>,
+}
+
+pub struct SecureStoreVault {
+    info: SecureStoreInfo,
+    manager: SecretsManager,
+    secret_value: Configuration<String>,
+}
+
+#[async_trait::async_trait]
+impl VaultTrait for SecureStoreVault {
+    fn get(&self) -> &Configuration<String> {
+        &self.secret_value
+    }
+
+    fn get_mut(&mut self) -> &mut Configuration<String> {
+        &mut self.secret_value
+    }
+
+    fn to_vault_kind(&self) -> VaultKind {
+        VaultKind::SecureStore(self.info.clone())
+    }
+
+    async fn save(&mut self) -> Result<(), VaultError> {
+        self.save_store()?;
+        Ok(())
+    }
+}
+
+impl SecureStoreVault {
+    /// Opens the store at `path`, creating it if it does not exist yet.
+    /// When `key_file` points to a missing file a new key is generated and exported there.
+    pub fn create(path: PathBuf, key_file: Option<PathBuf>) -> Result<Self, SecureStoreError> {
+        // paths are persisted in the config file, so they must not depend on the cwd
+        let info = SecureStoreInfo {
+            path: std::path::absolute(path)?,
+            key_file: key_file.map(std::path::absolute).transpose()?,
+        };
+        if info.path.exists() {
+            return Self::from_info(&info);
+        }
+        let manager = match info.key_file {
+            Some(ref key_file) if key_file.exists() => SecretsManager::new(key_file)?,
+            Some(ref key_file) => {
+                let manager = SecretsManager::new(KeySource::Csprng)?;
+                manager.export_key(key_file)?;
+                manager
+            }
+            None => SecretsManager::new(KeySource::Password(&Self::password(&info.path)?))?,
+        };
+        let mut res = Self {
+            info,
+            manager,
+            secret_value: Configuration::new(),
+        };
+        res.save_store()?;
+        Ok(res)
+    }
+
+    pub fn from_info(info: &SecureStoreInfo) -> Result<Self, SecureStoreError> {
+        let manager = match info.key_file {
+            Some(ref key_file) => SecretsManager::load(&info.path, key_file)?,
+            None => SecretsManager::load(
+                &info.path,
+                KeySource::Password(&Self::password(&info.path)?),
+            )?,
+        };
+        let mut secret_value = Configuration::new();
+        for name in manager.keys() {
+            let key_ref: KeyRef = name
+                .parse()
+                .map_err(|_| SecureStoreError::InvalidKey(name.to_string()))?;
+            secret_value.set(key_ref, manager.get(name)?);
+        }
+        Ok(Self {
+            info: info.clone(),
+            manager,
+            secret_value,
+        })
+    }
+
+    fn password(path: &Path) -> Result<String, SecureStoreError> {
+        std::env::var(PASSWORD_ENV)
+            .map_err(|_| SecureStoreError::MissingPassword(path.display().to_string()))
+    }
+
+    fn save_store(&mut self) -> Result<(), SecureStoreError> {
+        let stale: Vec<String> = self.manager.keys().map(|k| k.to_string()).collect();
+        for name in stale {
+            self.manager.remove(&name)?;
+        }
+        for key_ref in self.secret_value.keys("/") {
+            if let Some(value) = self.secret_value.get(&key_ref) {
+                self.manager.set(&key_ref.to_string(), value.as_str());
+            }
+        }
+        self.manager.save_as(&self.info.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_with_key_file() {
+        let dir = std::env::temp_dir().join(format!("secrets-manager-ss-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = dir.join("secrets.json");
+        let key = dir.join("secrets.key");
+
+        let mut vault = SecureStoreVault::create(store.clone(), Some(key.clone())).unwrap();
+        vault
+            .get_mut()
+            .set("dev.db.password".parse().unwrap(), "hunter2");
+        vault.get_mut().set("token".parse().unwrap(), "abc");
+        vault.save_store().unwrap();
+
+        let info = SecureStoreInfo {
+            path: store,
+            key_file: Some(key),
+        };
+        let vault = SecureStoreVault::from_info(&info).unwrap();
+        assert_eq!(
+            vault.get().get(&"dev.db.password".parse().unwrap()),
+            Some(&String::from("hunter2"))
+        );
+        assert_eq!(
+            vault.get().get(&"token".parse().unwrap()),
+            Some(&String::from("abc"))
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,957 @@
+use std::{
+    io::{BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use aes_gcm::{
+    aead::{consts::U32, rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    aes::Aes256,
+    AesGcm, Nonce,
+};
+use age::armor::{ArmoredReader, ArmoredWriter, Format};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha512};
+
+use crate::{write_atomically, Configuration, KeyRef};
+
+use super::{typed, VaultError, VaultKind, VaultTrait};
+
+/// SOPS encrypts values with a 32 bytes nonce
+type Cipher = AesGcm<Aes256, U32>;
+
+const SOPS_VERSION: &str = "3.8.1";
+const UNENCRYPTED_SUFFIX: &str = "_unencrypted";
+
+#[derive(thiserror::Error, Debug)]
+pub enum SopsError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    Age(#[from] age::DecryptError),
+    #[error(transparent)]
+    AgeEncrypt(#[from] age::EncryptError),
+    #[error("{0} is not a SOPS file")]
+    NotSops(String),
+    #[error("{0} is not supported")]
+    Unsupported(String),
+    #[error("None of the age or PGP keys of {0} could decrypt its data key")]
+    NoDataKey(String),
+    #[error("Invalid encrypted value at {0}")]
+    InvalidValue(String),
+    #[error("MAC mismatch, {0} was modified without SOPS")]
+    MacMismatch(String),
+    #[error("Invalid age recipient {0}")]
+    InvalidRecipient(String),
+    #[error("gpg failed: {0}")]
+    Gpg(String),
+    #[error("At least one age recipient or PGP fingerprint is required")]
+    NoRecipients,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SopsInfo {
+    path: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct Metadata {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    age: Vec<AgeKey>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pgp: Vec<PgpKey>,
+    /// With several groups, each one holds a Shamir share of the data key
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    key_groups: Vec<KeyGroup>,
+    /// Number of shares needed to rebuild the data key, all of them when 0
+    #[serde(default, skip_serializing_if = "is_zero")]
+    shamir_threshold: usize,
+    lastmodified: String,
+    mac: String,
+    #[serde(default = "default_suffix")]
+    unencrypted_suffix: String,
+    /// The MAC only covers the encrypted values
+    #[serde(default, skip_serializing_if = "is_false")]
+    mac_only_encrypted: bool,
+    version: String,
+    /// kms, gcp_kms, azure_kv... are kept as they are
+    #[serde(flatten)]
+    other: Map<String, Value>,
+}
+
+fn default_suffix() -> String {
+    String::from(UNENCRYPTED_SUFFIX)
+}
+
+fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct KeyGroup {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    age: Vec<AgeKey>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pgp: Vec<PgpKey>,
+    #[serde(flatten)]
+    other: Map<String, Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AgeKey {
+    recipient: String,
+    enc: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PgpKey {
+    #[serde(default)]
+    created_at: String,
+    enc: String,
+    fp: String,
+}
+
+/// Mounts a SOPS encrypted JSON or YAML file, nested maps are paths and scalars are keys.
+/// Lists are kept as they are but are not visible from the vault. Numbers and booleans
+/// are edited as text and keep their type as long as the new text parses as one
+pub struct SopsVault {
+    info: SopsInfo,
+    /// Decrypted document, without the `sops` metadata
+    document: Value,
+    metadata: Metadata,
+    data_key: Vec<u8>,
+    secret_value: Configuration<String>,
+    base_value: Configuration<String>,
+}
+
+#[async_trait::async_trait]
+impl VaultTrait for SopsVault {
+    fn get(&self) -> &Configuration<String> {
+        &self.secret_value
+    }
+
+    fn get_mut(&mut self) -> &mut Configuration<String> {
+        &mut self.secret_value
+    }
+
+    fn to_vault_kind(&self) -> VaultKind {
+        VaultKind::Sops(self.info.clone())
+    }
+
+    async fn save(&mut self) -> Result<(), VaultError> {
+        self.save_file().await?;
+        Ok(())
+    }
+}
+
+impl SopsVault {
+    /// Opens the file at `path`, or creates an empty one encrypted to the given keys
+    pub async fn create(
+        path: PathBuf,
+        age_recipients: Vec<String>,
+        pgp_fingerprints: Vec<String>,
+    ) -> Result<Self, SopsError> {
+        let info = SopsInfo {
+            path: std::path::absolute(path)?,
+        };
+        if info.path.exists() {
+            return Self::from_info(&info).await;
+        }
+        if age_recipients.is_empty() && pgp_fingerprints.is_empty() {
+            return Err(SopsError::NoRecipients);
+        }
+        let mut data_key = vec![0; 32];
+        OsRng.fill_bytes(&mut data_key);
+        let mut metadata = Metadata {
+            unencrypted_suffix: default_suffix(),
+            version: SOPS_VERSION.to_string(),
+            ..Default::default()
+        };
+        for recipient in age_recipients {
+            let enc = age_encrypt(&recipient, &data_key)?;
+            metadata.age.push(AgeKey { recipient, enc });
+        }
+        for fp in pgp_fingerprints {
+            let enc = gpg(&["--encrypt", "--armor", "--recipient", &fp], &data_key).await?;
+            metadata.pgp.push(PgpKey {
+                created_at: now_rfc3339(),
+                enc: String::from_utf8_lossy(&enc).to_string(),
+                fp,
+            });
+        }
+        let mut vault = Self {
+            info,
+            document: Value::Object(Map::new()),
+            metadata,
+            data_key,
+            secret_value: Configuration::new(),
+            base_value: Configuration::new(),
+        };
+        vault.save_file().await?;
+        Ok(vault)
+    }
+
+    pub async fn from_info(info: &SopsInfo) -> Result<Self, SopsError> {
+        Self::load(info, &age_keys()).await
+    }
+
+    /// Opens the file at `info.path`, `age_keys` is the content of an age key file
+    async fn load(info: &SopsInfo, age_keys: &str) -> Result<Self, SopsError> {
+        let name = info.path.display().to_string();
+        let content = std::fs::read_to_string(&info.path)?;
+        let mut document: Value = if is_yaml(&info.path) {
+            serde_yaml::from_str(&content)?
+        } else {
+            serde_json::from_str(&content)?
+        };
+        let metadata = document
+            .as_object_mut()
+            .and_then(|map| map.shift_remove("sops"))
+            .ok_or_else(|| SopsError::NotSops(name.clone()))?;
+        let metadata: Metadata = serde_json::from_value(metadata)?;
+        for option in ["encrypted_regex", "unencrypted_regex", "encrypted_suffix"] {
+            if metadata.other.contains_key(option) {
+                return Err(SopsError::Unsupported(option.to_string()));
+            }
+        }
+        let data_key = data_key(&metadata, age_keys)
+            .await
+            .ok_or_else(|| SopsError::NoDataKey(name.clone()))?;
+
+        let mut hash = Sha512::new();
+        walk(
+            &mut document,
+            &mut Vec::new(),
+            &metadata.unencrypted_suffix,
+            &mut |value, path, encrypted| {
+                if encrypted {
+                    *value = decrypt(value, &data_key, &aad(path))?;
+                }
+                if encrypted || !metadata.mac_only_encrypted {
+                    hash.update(to_bytes(value));
+                }
+                Ok(())
+            },
+        )?;
+        let mac = format!("{:X}", hash.finalize());
+        let stored = decrypt(
+            &Value::String(metadata.mac.clone()),
+            &data_key,
+            &metadata.lastmodified,
+        )?;
+        if stored.as_str() != Some(mac.as_str()) {
+            return Err(SopsError::MacMismatch(name));
+        }
+
+        let mut secret_value = Configuration::new();
+        collect(&document, PathBuf::from("/"), &mut secret_value);
+        Ok(Self {
+            info: info.clone(),
+            document,
+            metadata,
+            data_key,
+            base_value: secret_value.clone(),
+            secret_value,
+        })
+    }
+
+    async fn save_file(&mut self) -> Result<(), SopsError> {
+        // keys are compared as stored, a value inherited from a parent map is a key of its own
+        for key_ref in self.secret_value.changed_keys(&self.base_value) {
+            match self.secret_value.get_exact(&key_ref) {
+                Some(value) => insert(&mut self.document, &key_ref, value),
+                None => remove(&mut self.document, &key_ref),
+            }
+        }
+
+        let mut encrypted = self.document.clone();
+        let mut hash = Sha512::new();
+        walk(
+            &mut encrypted,
+            &mut Vec::new(),
+            &self.metadata.unencrypted_suffix,
+            &mut |value, path, should_encrypt| {
+                if should_encrypt || !self.metadata.mac_only_encrypted {
+                    hash.update(to_bytes(value));
+                }
+                if should_encrypt {
+                    *value = Value::String(encrypt(value, &self.data_key, &aad(path))?);
+                }
+                Ok(())
+            },
+        )?;
+        self.metadata.lastmodified = now_rfc3339();
+        let mac = format!("{:X}", hash.finalize());
+        self.metadata.mac = encrypt(
+            &Value::String(mac),
+            &self.data_key,
+            &self.metadata.lastmodified,
+        )?;
+        if let Value::Object(ref mut map) = encrypted {
+            map.insert(String::from("sops"), serde_json::to_value(&self.metadata)?);
+        }
+        let content = if is_yaml(&self.info.path) {
+            serde_yaml::to_string(&encrypted)?
+        } else {
+            serde_json::to_string_pretty(&encrypted)? + "\n"
+        };
+        write_atomically(&self.info.path, content.as_bytes())?;
+        self.base_value = self.secret_value.clone();
+        Ok(())
+    }
+}
+
+fn is_yaml(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("yaml" | "yml")
+    )
+}
+
+/// Calls `f` on every scalar of the document, in order, with its path and whether it is encrypted.
+/// Values in lists share the path of the list, like SOPS does
+fn walk<'a>(
+    value: &'a mut Value,
+    path: &mut Vec<&'a str>,
+    suffix: &str,
+    f: &mut impl FnMut(&mut Value, &[&str], bool) -> Result<(), SopsError>,
+) -> Result<(), SopsError> {
+    let encrypted = !path.iter().any(|p| p.ends_with(suffix));
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                path.push(key);
+                walk(value, path, suffix, f)?;
+                path.pop();
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                walk(value, path, suffix, f)?;
+            }
+        }
+        Value::Null => {}
+        value => f(value, path, encrypted)?,
+    }
+    Ok(())
+}
+
+fn aad(path: &[&str]) -> String {
+    path.iter().map(|p| format!("{}:", p)).collect()
+}
+
+/// Bytes of a value added to the MAC, booleans are capitalized as in SOPS
+fn to_bytes(value: &Value) -> Vec<u8> {
+    match value {
+        Value::String(s) => s.as_bytes().to_vec(),
+        Value::Bool(true) => b"True".to_vec(),
+        Value::Bool(false) => b"False".to_vec(),
+        Value::Number(n) => n.to_string().into_bytes(),
+        _ => Vec::new(),
+    }
+}
+
+fn encrypt(value: &Value, key: &[u8], aad: &str) -> Result<String, SopsError> {
+    let (plaintext, kind) = match value {
+        Value::String(s) if s.is_empty() => return Ok(String::new()),
+        Value::String(s) => (s.clone(), "str"),
+        Value::Bool(_) => (
+            String::from_utf8_lossy(&to_bytes(value)).to_string(),
+            "bool",
+        ),
+        Value::Number(n) if n.is_f64() => (n.to_string(), "float"),
+        Value::Number(n) => (n.to_string(), "int"),
+        _ => return Ok(String::new()),
+    };
+    let cipher = Cipher::new_from_slice(key).map_err(|_| SopsError::InvalidValue(aad.into()))?;
+    let iv = Cipher::generate_nonce(&mut OsRng);
+    let mut data = cipher
+        .encrypt(
+            &iv,
+            Payload {
+                msg: plaintext.as_bytes(),
+                aad: aad.as_bytes(),
+            },
+        )
+        .map_err(|_| SopsError::InvalidValue(aad.into()))?;
+    let tag = data.split_off(data.len() - 16);
+    Ok(format!(
+        "ENC[AES256_GCM,data:{},iv:{},tag:{},type:{}]",
+        STANDARD.encode(data),
+        STANDARD.encode(iv),
+        STANDARD.encode(tag),
+        kind
+    ))
+}
+
+fn decrypt(value: &Value, key: &[u8], aad: &str) -> Result<Value, SopsError> {
+    let invalid = || SopsError::InvalidValue(aad.trim_end_matches(':').replace(':', "."));
+    let Some(encrypted) = value.as_str() else {
+        return Err(invalid());
+    };
+    if encrypted.is_empty() {
+        return Ok(Value::String(String::new()));
+    }
+    let fields = encrypted
+        .strip_prefix("ENC[AES256_GCM,")
+        .and_then(|v| v.strip_suffix(']'))
+        .ok_or_else(invalid)?;
+    let field = |name: &str| {
+        fields
+            .split(',')
+            .find_map(|f| f.strip_prefix(name)?.strip_prefix(':'))
+            .ok_or_else(invalid)
+    };
+    let decode = |name: &str| STANDARD.decode(field(name)?).map_err(|_| invalid());
+    let mut data = decode("data")?;
+    data.extend(decode("tag")?);
+    let iv = decode("iv")?;
+    if iv.len() != 32 {
+        return Err(invalid());
+    }
+    let cipher = Cipher::new_from_slice(key).map_err(|_| invalid())?;
+    let plaintext = cipher
+        .decrypt(
+            Nonce::from_slice(&iv),
+            Payload {
+                msg: &data,
+                aad: aad.as_bytes(),
+            },
+        )
+        .map_err(|_| invalid())?;
+    let plaintext = String::from_utf8(plaintext).map_err(|_| invalid())?;
+    Ok(match field("type")? {
+        "int" | "float" => serde_json::from_str(&plaintext).map_err(|_| invalid())?,
+        "bool" => Value::Bool(plaintext.eq_ignore_ascii_case("true")),
+        _ => Value::String(plaintext),
+    })
+}
+
+/// Flattens the maps of the document into `config`, lists are skipped
+fn collect(value: &Value, path: PathBuf, config: &mut Configuration<String>) {
+    let Value::Object(map) = value else {
+        return;
+    };
+    for (key, value) in map {
+        let text = match value {
+            Value::Object(_) => {
+                collect(value, path.join(key), config);
+                continue;
+            }
+            Value::String(s) => s.clone(),
+            Value::Bool(_) | Value::Number(_) => value.to_string(),
+            Value::Null | Value::Array(_) => continue,
+        };
+        config.set(
+            KeyRef {
+                path: path.clone(),
+                key: key.clone(),
+            },
+            text,
+        );
+    }
+}
+
+fn insert(document: &mut Value, key_ref: &KeyRef, value: &str) {
+    let mut current = document;
+    for part in key_ref.path.iter().skip(1) {
+        let map = ensure_object(current);
+        current = map
+            .entry(part.to_string_lossy().to_string())
+            .or_insert_with(|| json!({}));
+    }
+    let map = ensure_object(current);
+    let value = typed(map.get(&key_ref.key), value);
+    map.insert(key_ref.key.clone(), value);
+}
+
+fn ensure_object(value: &mut Value) -> &mut Map<String, Value> {
+    if !value.is_object() {
+        *value = Value::Object(Map::new());
+    }
+    value.as_object_mut().unwrap()
+}
+
+fn remove(document: &mut Value, key_ref: &KeyRef) {
+    let mut current = Some(document);
+    for part in key_ref.path.iter().skip(1) {
+        current = current.and_then(|v| v.get_mut(part.to_string_lossy().as_ref()));
+    }
+    if let Some(Value::Object(map)) = current {
+        map.shift_remove(&key_ref.key);
+    }
+}
+
+/// Decrypts the data key, or enough of its shares when the file has several key groups
+async fn data_key(metadata: &Metadata, age_keys: &str) -> Option<Vec<u8>> {
+    let groups = &metadata.key_groups;
+    if groups.is_empty() {
+        return decrypt_key(&metadata.age, &metadata.pgp, age_keys).await;
+    }
+    let mut shares = Vec::new();
+    for group in groups {
+        if let Some(share) = decrypt_key(&group.age, &group.pgp, age_keys).await {
+            shares.push(share);
+        }
+    }
+    // a single group holds the data key itself, like SOPS writes it
+    if groups.len() == 1 {
+        return shares.pop();
+    }
+    let threshold = match metadata.shamir_threshold {
+        0 => groups.len(),
+        threshold => threshold,
+    };
+    if shares.len() < threshold {
+        return None;
+    }
+    shamir::combine(&shares)
+}
+
+/// Decrypts a key with the first age identity or PGP key that can
+async fn decrypt_key(age: &[AgeKey], pgp: &[PgpKey], age_keys: &str) -> Option<Vec<u8>> {
+    if let Some(data_key) = age_decrypt(age, age_keys) {
+        return Some(data_key);
+    }
+    for key in pgp {
+        if let Ok(data_key) = gpg(&["--decrypt"], key.enc.as_bytes()).await {
+            return Some(data_key);
+        }
+    }
+    None
+}
+
+/// Decrypts a key with the identities of the age key file `age_keys`
+fn age_decrypt(age: &[AgeKey], age_keys: &str) -> Option<Vec<u8>> {
+    if age.is_empty() {
+        return None;
+    }
+    let identities = age::IdentityFile::from_buffer(BufReader::new(age_keys.as_bytes()))
+        .and_then(|file| file.into_identities().map_err(std::io::Error::other))
+        .unwrap_or_default();
+    for key in age {
+        let Ok(decryptor) = age::Decryptor::new(ArmoredReader::new(key.enc.as_bytes())) else {
+            continue;
+        };
+        let Ok(mut reader) = decryptor.decrypt(identities.iter().map(|i| i.as_ref())) else {
+            continue;
+        };
+        let mut data_key = Vec::new();
+        if reader.read_to_end(&mut data_key).is_ok() {
+            return Some(data_key);
+        }
+    }
+    None
+}
+
+/// Content of SOPS_AGE_KEY, SOPS_AGE_KEY_FILE or the default sops key file
+fn age_keys() -> String {
+    match std::env::var("SOPS_AGE_KEY") {
+        Ok(keys) => keys,
+        Err(_) => {
+            let path = match std::env::var_os("SOPS_AGE_KEY_FILE") {
+                Some(path) => PathBuf::from(path),
+                None => {
+                    let config = std::env::var_os("XDG_CONFIG_HOME")
+                        .map(PathBuf::from)
+                        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".config")))
+                        .unwrap_or_default();
+                    config.join("sops").join("age").join("keys.txt")
+                }
+            };
+            std::fs::read_to_string(path).unwrap_or_default()
+        }
+    }
+}
+
+fn age_encrypt(recipient: &str, data: &[u8]) -> Result<String, SopsError> {
+    let recipient: age::x25519::Recipient = recipient
+        .parse()
+        .map_err(|_| SopsError::InvalidRecipient(recipient.to_string()))?;
+    let encryptor = age::Encryptor::with_recipients(std::iter::once(&recipient as _))?;
+    let mut out = Vec::new();
+    let armor = ArmoredWriter::wrap_output(&mut out, Format::AsciiArmor)?;
+    let mut writer = encryptor.wrap_output(armor)?;
+    writer.write_all(data)?;
+    writer.finish()?.finish()?;
+    Ok(String::from_utf8_lossy(&out).to_string())
+}
+
+async fn gpg(args: &[&str], input: &[u8]) -> Result<Vec<u8>, SopsError> {
+    use tokio::io::AsyncWriteExt;
+    let mut child = tokio::process::Command::new("gpg")
+        .args(["--batch", "--quiet", "--yes"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input).await?;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(SopsError::Gpg(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// Current UTC time formatted like SOPS, e.g 2024-05-01T12:00:00Z
+fn now_rfc3339() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Shamir secret sharing over GF(2^8), compatible with the implementation used by SOPS.
+/// A share is the value of a random polynomial for each byte of the secret,
+/// followed by the x coordinate it was evaluated at
+mod shamir {
+    #[cfg(test)]
+    use aes_gcm::aead::{rand_core::RngCore, OsRng};
+
+    /// Product in GF(2^8) with the AES polynomial
+    pub fn mult(a: u8, b: u8) -> u8 {
+        let mut res = 0u8;
+        for i in (0..8).rev() {
+            res = (0u8.wrapping_sub(b >> i & 1) & a)
+                ^ (0u8.wrapping_sub(res >> 7) & 0x1b)
+                ^ res.wrapping_add(res);
+        }
+        res
+    }
+
+    /// Multiplicative inverse, a^254
+    fn inverse(a: u8) -> u8 {
+        let mut res = 1;
+        for _ in 0..254 {
+            res = mult(res, a);
+        }
+        res
+    }
+
+    /// Value at 0 of the polynomial going through `points`
+    fn interpolate(points: &[(u8, u8)]) -> u8 {
+        let mut res = 0;
+        for (i, &(xi, yi)) in points.iter().enumerate() {
+            let mut basis = 1;
+            for (j, &(xj, _)) in points.iter().enumerate() {
+                if i != j {
+                    basis = mult(basis, mult(xj, inverse(xi ^ xj)));
+                }
+            }
+            res ^= mult(yi, basis);
+        }
+        res
+    }
+
+    /// Secret shared by `shares`, `None` if they are malformed
+    pub fn combine(shares: &[Vec<u8>]) -> Option<Vec<u8>> {
+        let len = shares.first()?.len().checked_sub(1)?;
+        let mut xs: Vec<u8> = Vec::new();
+        for share in shares {
+            let x = *share.last()?;
+            if share.len() != len + 1 || x == 0 || xs.contains(&x) {
+                return None;
+            }
+            xs.push(x);
+        }
+        let secret = (0..len)
+            .map(|i| {
+                let points: Vec<_> = shares.iter().map(|s| (s[len], s[i])).collect();
+                interpolate(&points)
+            })
+            .collect();
+        Some(secret)
+    }
+
+    /// Splits `secret` into `parts` shares, any `threshold` of them rebuild it
+    #[cfg(test)]
+    pub fn split(secret: &[u8], parts: usize, threshold: usize) -> Vec<Vec<u8>> {
+        assert!(0 < threshold && threshold <= parts && parts < 256);
+        let mut xs: Vec<u8> = Vec::new();
+        while xs.len() < parts {
+            let x = OsRng.next_u32() as u8;
+            if x != 0 && !xs.contains(&x) {
+                xs.push(x);
+            }
+        }
+        let mut shares: Vec<Vec<u8>> = xs.iter().map(|_| Vec::new()).collect();
+        for &byte in secret {
+            let mut coefficients = vec![0u8; threshold];
+            OsRng.fill_bytes(&mut coefficients);
+            coefficients[0] = byte;
+            for (share, &x) in shares.iter_mut().zip(&xs) {
+                let y = coefficients.iter().rev().fold(0, |y, &c| mult(y, x) ^ c);
+                share.push(y);
+            }
+        }
+        for (share, x) in shares.iter_mut().zip(xs) {
+            share.push(x);
+        }
+        shares
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use age::secrecy::ExposeSecret;
+
+    use super::*;
+
+    #[test]
+    fn values_round_trip() {
+        let key = [7u8; 32];
+        for value in [
+            json!("hunter2"),
+            json!(42),
+            json!(1.5),
+            json!(true),
+            json!(""),
+        ] {
+            let encrypted = encrypt(&value, &key, "db:pass:").unwrap();
+            let decrypted = decrypt(&Value::String(encrypted), &key, "db:pass:").unwrap();
+            assert_eq!(decrypted, value);
+        }
+        let encrypted = encrypt(&json!("x"), &key, "db:pass:").unwrap();
+        assert!(decrypt(&Value::String(encrypted), &key, "db:user:").is_err());
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "secrets-manager-sops-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn key(key: &str) -> KeyRef {
+        key.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn yaml_file_round_trip() {
+        let dir = temp_dir("yaml");
+        let identity = age::x25519::Identity::generate();
+        let age_keys = identity.to_string().expose_secret().to_string();
+        let path = dir.join("secrets.yaml");
+
+        let mut vault = SopsVault::create(
+            path.clone(),
+            vec![identity.to_public().to_string()],
+            Vec::new(),
+        )
+        .await
+        .unwrap();
+        let info = vault.info.clone();
+        vault.get_mut().set(key("db.PASS"), "hunter2");
+        vault.get_mut().set(key("db.HOST_unencrypted"), "localhost");
+        vault.get_mut().set(key("TOKEN"), "x");
+        vault.save().await.unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("hunter2"));
+        assert!(content.contains("HOST_unencrypted: localhost"));
+
+        // edits made by hand outside of the encrypted values are kept, others break the MAC
+        std::fs::write(&path, content.replace("localhost", "example.com")).unwrap();
+        assert!(matches!(
+            SopsVault::load(&info, &age_keys).await,
+            Err(SopsError::MacMismatch(_))
+        ));
+        std::fs::write(&path, content).unwrap();
+        let mut vault = SopsVault::load(&info, &age_keys).await.unwrap();
+        assert_eq!(vault.get().get(&key("db.PASS")).unwrap(), "hunter2");
+
+        // same value as the parent TOKEN, still a key of its own
+        vault.get_mut().set(key("db.TOKEN"), "x");
+        vault.save().await.unwrap();
+        let mut vault = SopsVault::load(&info, &age_keys).await.unwrap();
+        assert_eq!(vault.get().get_exact(&key("db.TOKEN")).unwrap(), "x");
+        vault.get_mut().remove(&key("db.TOKEN"));
+        vault.save().await.unwrap();
+        let vault = SopsVault::load(&info, &age_keys).await.unwrap();
+        assert_eq!(vault.get().get_exact(&key("db.TOKEN")), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn mac_only_covers_encrypted_values() {
+        let dir = temp_dir("mac-only");
+        let identity = age::x25519::Identity::generate();
+        let age_keys = identity.to_string().expose_secret().to_string();
+        let path = dir.join("secrets.yaml");
+        let mut vault = SopsVault::create(
+            path.clone(),
+            vec![identity.to_public().to_string()],
+            Vec::new(),
+        )
+        .await
+        .unwrap();
+        vault.metadata.mac_only_encrypted = true;
+        vault.get_mut().set(key("db.PASS"), "hunter2");
+        vault.get_mut().set(key("db.HOST_unencrypted"), "localhost");
+        vault.save().await.unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.contains("mac_only_encrypted: true"));
+
+        // unencrypted values can be edited without SOPS
+        std::fs::write(&path, content.replace("localhost", "example.com")).unwrap();
+        let vault = SopsVault::load(&vault.info, &age_keys).await.unwrap();
+        assert_eq!(
+            vault.get().get(&key("db.HOST_unencrypted")).unwrap(),
+            "example.com"
+        );
+        assert_eq!(vault.get().get(&key("db.PASS")).unwrap(), "hunter2");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn edits_keep_the_type_of_values() {
+        let mut document = json!({ "db": { "PORT": 5432, "RATIO": 0.5, "DEBUG": true } });
+        insert(&mut document, &key("db.PORT"), "6543");
+        insert(&mut document, &key("db.RATIO"), "1.5");
+        insert(&mut document, &key("db.DEBUG"), "false");
+        insert(&mut document, &key("db.NAME"), "42");
+        assert_eq!(
+            document,
+            json!({ "db": { "PORT": 6543, "RATIO": 1.5, "DEBUG": false, "NAME": "42" } })
+        );
+        insert(&mut document, &key("db.PORT"), "default");
+        assert_eq!(document["db"]["PORT"], json!("default"));
+    }
+
+    #[test]
+    fn shamir_shares_round_trip() {
+        // example of FIPS-197, the field used by SOPS is the one of AES
+        assert_eq!(shamir::mult(0x57, 0x83), 0xc1);
+        let secret: Vec<u8> = (0..32).collect();
+        let shares = shamir::split(&secret, 3, 2);
+        for pair in [[0, 1], [0, 2], [1, 2]] {
+            let shares = pair.map(|i| shares[i].clone());
+            assert_eq!(shamir::combine(&shares).unwrap(), secret);
+        }
+        assert_eq!(shamir::combine(&shares).unwrap(), secret);
+        assert_ne!(shamir::combine(&shares[..1]).unwrap(), secret);
+    }
+
+    #[tokio::test]
+    async fn key_groups_share_the_data_key() {
+        let dir = temp_dir("groups");
+        let identities: Vec<_> = (0..3).map(|_| age::x25519::Identity::generate()).collect();
+        let age_keys = |ids: &[usize]| {
+            ids.iter()
+                .map(|&i| identities[i].to_string().expose_secret().to_string())
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let recipient = identities[0].to_public().to_string();
+        let mut vault = SopsVault::create(dir.join("secrets.json"), vec![recipient], Vec::new())
+            .await
+            .unwrap();
+        // one group per identity, any two of them decrypt the file
+        vault.metadata.age.clear();
+        vault.metadata.shamir_threshold = 2;
+        let shares = shamir::split(&vault.data_key, 3, 2);
+        for (identity, share) in identities.iter().zip(shares) {
+            let recipient = identity.to_public().to_string();
+            let enc = age_encrypt(&recipient, &share).unwrap();
+            vault.metadata.key_groups.push(KeyGroup {
+                age: vec![AgeKey { recipient, enc }],
+                pgp: Vec::new(),
+                other: Map::new(),
+            });
+        }
+        vault.get_mut().set(key("db.PASS"), "hunter2");
+        vault.save().await.unwrap();
+
+        for (i, ids) in [[0, 1], [1, 2], [0, 2]].into_iter().enumerate() {
+            let mut vault = SopsVault::load(&vault.info, &age_keys(&ids)).await.unwrap();
+            let expected = format!("hunter{}", i + 2);
+            assert_eq!(vault.get().get(&key("db.PASS")), Some(&expected));
+            vault
+                .get_mut()
+                .set(key("db.PASS"), format!("hunter{}", i + 3));
+            vault.save().await.unwrap();
+        }
+        assert!(matches!(
+            SopsVault::load(&vault.info, &age_keys(&[2])).await,
+            Err(SopsError::NoDataKey(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Reads and edits a file encrypted by `sops` itself, then decrypts it with `sops`
+    #[tokio::test]
+    #[ignore = "needs the sops binary"]
+    async fn sops_interop() {
+        let dir = temp_dir("interop");
+        let identity = age::x25519::Identity::generate();
+        let age_keys = identity.to_string().expose_secret().to_string();
+        let path = dir.join("secrets.yaml");
+        std::fs::write(&path, "db:\n  PASS: hunter2\n  PORT: 5432\n  DEBUG: true\n").unwrap();
+        let sops = |args: &[&str]| {
+            let output = std::process::Command::new("sops")
+                .env("SOPS_AGE_KEY", &age_keys)
+                .args(args)
+                .arg(&path)
+                .output()
+                .expect("sops is installed");
+            assert!(output.status.success(), "{:?}", output);
+            String::from_utf8(output.stdout).unwrap()
+        };
+        let recipient = identity.to_public().to_string();
+        sops(&["--encrypt", "--in-place", "--age", &recipient]);
+
+        let info = SopsInfo { path: path.clone() };
+        let mut vault = SopsVault::load(&info, &age_keys).await.unwrap();
+        assert_eq!(vault.get().get(&key("db.PASS")).unwrap(), "hunter2");
+        assert_eq!(vault.get().get(&key("db.PORT")).unwrap(), "5432");
+        vault.get_mut().set(key("db.PORT"), "6543");
+        vault.get_mut().set(key("db.USER"), "joe");
+        vault.save().await.unwrap();
+
+        let decrypted: serde_yaml::Value = serde_yaml::from_str(&sops(&["--decrypt"])).unwrap();
+        let expected: serde_yaml::Value = serde_yaml::from_str(
+            "db:\n  PASS: hunter2\n  PORT: 6543\n  DEBUG: true\n  USER: joe\n",
+        )
+        .unwrap();
+        assert_eq!(decrypted, expected);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
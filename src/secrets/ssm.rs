@@ -0,0 +1,243 @@
+use aws_sdk_ssm::{types::ParameterType, Client};
+use serde::{Deserialize, Serialize};
+
+use crate::Configuration;
+
+use super::{
+    aws::{default_profile, AwsSecretVault},
+    key_from_name, key_name, VaultError, VaultKind, VaultTrait,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum SsmError {
+    #[error(transparent)]
+    Ssm(Box<aws_sdk_ssm::Error>),
+    #[error("Invalid parameter prefix {0}, it must start with `/`")]
+    InvalidPrefix(String),
+}
+
+// boxed, the sdk error is large enough to bloat every `Result` it goes through
+impl From<aws_sdk_ssm::Error> for SsmError {
+    fn from(e: aws_sdk_ssm::Error) -> Self {
+        Self::Ssm(Box::new(e))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AwsSsmInfo {
+    /// Parameter hierarchy holding the configuration, e.g `/app`
+    prefix: String,
+    #[serde(default = "default_profile")]
+    profile_name: String,
+}
+
+/// Stores each key as a `SecureString` parameter named `<prefix>/<path>/<key>`
+pub struct AwsSsmVault {
+    client: Client,
+    info: AwsSsmInfo,
+    secret_value: Configuration<String>,
+    /// Value as loaded from SSM, only keys that changed are written back
+    base_value: Configuration<String>,
+}
+
+#[async_trait::async_trait]
+impl VaultTrait for AwsSsmVault {
+    fn get(&self) -> &Configuration<String> {
+        &self.secret_value
+    }
+
+    fn get_mut(&mut self) -> &mut Configuration<String> {
+        &mut self.secret_value
+    }
+
+    fn to_vault_kind(&self) -> VaultKind {
+        VaultKind::AwsSsm(self.info.clone())
+    }
+
+    async fn save(&mut self) -> Result<(), VaultError> {
+        self.save_parameters().await?;
+        Ok(())
+    }
+}
+
+impl AwsSsmVault {
+    pub async fn create(prefix: String, profile_name: String) -> Result<Self, SsmError> {
+        let prefix = prefix.trim_end_matches('/');
+        if !prefix.starts_with('/') {
+            return Err(SsmError::InvalidPrefix(prefix.to_string()));
+        }
+        let info = AwsSsmInfo {
+            prefix: prefix.to_string(),
+            profile_name,
+        };
+        Self::from_info(&info).await
+    }
+
+    pub async fn from_info(info: &AwsSsmInfo) -> Result<Self, SsmError> {
+        let client = Client::new(&AwsSecretVault::sdk_config(&info.profile_name).await);
+        Self::with_client(client, info).await
+    }
+
+    async fn with_client(client: Client, info: &AwsSsmInfo) -> Result<Self, SsmError> {
+        let mut value = Configuration::new();
+        let mut pages = client
+            .get_parameters_by_path()
+            .path(&info.prefix)
+            .recursive(true)
+            .with_decryption(true)
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(aws_sdk_ssm::Error::from)?;
+            for parameter in page.parameters() {
+                let (Some(name), Some(parameter_value)) = (parameter.name(), parameter.value())
+                else {
+                    continue;
+                };
+                if let Some(key_ref) = key_from_name(&info.prefix, name) {
+                    value.set(key_ref, parameter_value);
+                }
+            }
+        }
+        Ok(Self {
+            client,
+            info: info.clone(),
+            base_value: value.clone(),
+            secret_value: value,
+        })
+    }
+
+    async fn save_parameters(&mut self) -> Result<(), SsmError> {
+        // keys are compared as stored, a value inherited from a parent path is a parameter of its own
+        let mut removed = Vec::new();
+        for key_ref in self.secret_value.changed_keys(&self.base_value) {
+            let name = key_name(&self.info.prefix, &key_ref);
+            let Some(value) = self.secret_value.get_exact(&key_ref) else {
+                removed.push(name);
+                continue;
+            };
+            self.client
+                .put_parameter()
+                .name(name)
+                .value(value)
+                .r#type(ParameterType::SecureString)
+                .overwrite(true)
+                .send()
+                .await
+                .map_err(aws_sdk_ssm::Error::from)?;
+        }
+        // DeleteParameters accepts at most 10 names per call
+        for names in removed.chunks(10) {
+            self.client
+                .delete_parameters()
+                .set_names(Some(names.to_vec()))
+                .send()
+                .await
+                .map_err(aws_sdk_ssm::Error::from)?;
+        }
+        self.base_value = self.secret_value.clone();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    };
+
+    use aws_sdk_ssm::config::{retry::RetryConfig, BehaviorVersion, Credentials, Region};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{secrets::fake_aws::FakeAws, KeyRef};
+
+    /// Parameters by name, answering the few SSM operations used by the vault
+    #[derive(Clone, Default)]
+    struct FakeSsm(Arc<Mutex<BTreeMap<String, String>>>);
+
+    impl FakeSsm {
+        fn handle(&self, operation: &str, request: &Value) -> Result<Value, &'static str> {
+            let mut parameters = self.0.lock().unwrap();
+            match operation {
+                "GetParametersByPath" => {
+                    let path = request["Path"].as_str().unwrap();
+                    let found: Vec<Value> = parameters
+                        .iter()
+                        .filter(|(name, _)| name.starts_with(path))
+                        .map(|(name, value)| {
+                            json!({ "Name": name, "Value": value, "Type": "SecureString" })
+                        })
+                        .collect();
+                    Ok(json!({ "Parameters": found }))
+                }
+                "PutParameter" => {
+                    let name = request["Name"].as_str().unwrap();
+                    let value = request["Value"].as_str().unwrap();
+                    parameters.insert(name.to_string(), value.to_string());
+                    Ok(json!({ "Version": 1 }))
+                }
+                "DeleteParameters" => {
+                    let names = request["Names"].as_array().unwrap();
+                    for name in names {
+                        parameters.remove(name.as_str().unwrap());
+                    }
+                    Ok(json!({ "DeletedParameters": names, "InvalidParameters": [] }))
+                }
+                _ => Err("InvalidAction"),
+            }
+        }
+
+        fn client(&self) -> Client {
+            let fake = self.clone();
+            let config = aws_sdk_ssm::Config::builder()
+                .behavior_version(BehaviorVersion::latest())
+                .region(Region::new("us-east-1"))
+                .credentials_provider(Credentials::new("id", "secret", None, None, "test"))
+                .retry_config(RetryConfig::disabled())
+                .http_client(
+                    FakeAws::new(move |op, request| fake.handle(op, request)).http_client(),
+                )
+                .build();
+            Client::from_conf(config)
+        }
+
+        fn names(&self) -> Vec<String> {
+            self.0.lock().unwrap().keys().cloned().collect()
+        }
+    }
+
+    fn key(key: &str) -> KeyRef {
+        key.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn keys_shadowing_a_parent_are_saved() {
+        let fake = FakeSsm::default();
+        let info = AwsSsmInfo {
+            prefix: String::from("/app"),
+            profile_name: default_profile(),
+        };
+        for name in ["/app/X", "/app/dev/X", "/app/Y"] {
+            fake.0
+                .lock()
+                .unwrap()
+                .insert(name.to_string(), String::from("1"));
+        }
+        let mut vault = AwsSsmVault::with_client(fake.client(), &info)
+            .await
+            .unwrap();
+        // same value as the parent /X, still a parameter of its own
+        vault.get_mut().remove(&key("dev.X"));
+        vault.get_mut().set(key("dev.Y"), "1");
+        vault.save_parameters().await.unwrap();
+        assert_eq!(fake.names(), ["/app/X", "/app/Y", "/app/dev/Y"]);
+
+        let vault = AwsSsmVault::with_client(fake.client(), &info)
+            .await
+            .unwrap();
+        assert_eq!(vault.get().get_exact(&key("dev.X")), None);
+        assert_eq!(vault.get().get_exact(&key("dev.Y")).unwrap(), "1");
+    }
+}
@@ -0,0 +1,76 @@
+//! Reference plugin for the `--exec` vault provider, storing the secrets in a plain JSON file.
+//!
+//! usage: exec-plugin <STORE> [--no-set] [--version=N]
+//!
+//! Reads one request on stdin and writes one response on stdout, see `ExecVault`
+//! for the protocol. `--no-set` only advertises `get`, so that whole saves are used,
+//! `--version` answers with another protocol version.
+
+use std::{collections::BTreeMap, io::Read};
+
+use serde_json::{json, Value};
+
+const VERSION: u64 = 1;
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let store = args.next().expect("usage: exec-plugin <STORE> [--no-set]");
+    let mut operations = vec!["get", "set"];
+    let mut version = VERSION;
+    for arg in args {
+        if arg == "--no-set" {
+            operations.retain(|o| *o != "set");
+        } else if let Some(v) = arg.strip_prefix("--version=") {
+            version = v.parse().expect("invalid version");
+        }
+    }
+
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input).unwrap();
+    let mut response = match serde_json::from_str::<Value>(&input) {
+        Ok(request) if request["version"] != json!(VERSION) => json!({
+            "error": format!("unsupported protocol version {}", request["version"]),
+        }),
+        Ok(request) => {
+            handle(&store, &request, &operations).unwrap_or_else(|e| json!({ "error": e }))
+        }
+        Err(e) => json!({ "error": e.to_string() }),
+    };
+    response["version"] = json!(version);
+    println!("{}", response);
+}
+
+fn handle(store: &str, request: &Value, operations: &[&str]) -> Result<Value, String> {
+    let mut values: BTreeMap<String, String> = match std::fs::read_to_string(store) {
+        Ok(data) => serde_json::from_str(&data).map_err(|e| e.to_string())?,
+        Err(_) => BTreeMap::new(),
+    };
+    let operation = request["operation"].as_str().unwrap_or_default();
+    if !matches!(operation, "load" | "save") && !operations.contains(&operation) {
+        return Err(format!("unsupported operation {}", operation));
+    }
+    let key = request["key"].as_str().unwrap_or_default().to_string();
+    match operation {
+        "load" => Ok(json!({ "values": values, "operations": operations })),
+        "get" => Ok(json!({ "value": values.get(&key) })),
+        "set" => {
+            match request["value"].as_str() {
+                Some(value) => values.insert(key, value.to_string()),
+                None => values.remove(&key),
+            };
+            write(store, &values)
+        }
+        "save" => {
+            values =
+                serde_json::from_value(request["values"].clone()).map_err(|e| e.to_string())?;
+            write(store, &values)
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn write(store: &str, values: &BTreeMap<String, String>) -> Result<Value, String> {
+    let data = serde_json::to_string_pretty(values).map_err(|e| e.to_string())?;
+    std::fs::write(store, data).map_err(|e| e.to_string())?;
+    Ok(json!({}))
+}